@@ -0,0 +1,1295 @@
+//! Command line options and the files they point at
+use clap::{Parser, ValueEnum};
+use rand::{distributions::WeightedIndex, prelude::Distribution, Rng};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+use crate::{connection::*, history::*, turns::*, ChatroomError};
+
+/// Simple IRC Logger Application
+#[derive(Parser, Debug, Serialize)]
+#[command(name = "chatroom", author, version, about, long_about = None)]
+pub struct Args {
+    /// Model name to identify the instance
+    #[arg(short, long, required_unless_present = "list_models", default_value = "")]
+    pub(crate) model: String,
+
+    /// IRC server address (e.g., irc.libera.chat)
+    #[arg(short, long, default_value = "irc.libera.chat")]
+    pub(crate) server: String,
+
+    /// IRC server port
+    #[arg(short, long, default_value_t = 6667)]
+    pub(crate) port: u16,
+
+    /// IRC channel to join (e.g., #rust), can be given multiple times. Defaults to
+    /// #chat_0098 when no channels are given here or in --channels-from-file
+    #[arg(short, long = "channel")]
+    pub(crate) channels: Vec<String>,
+
+    /// Also join the channels listed in this file, one `#channel[,model[,key]]` per line
+    #[arg(long)]
+    pub(crate) channels_from_file: Option<PathBuf>,
+
+    /// IRC nickname
+    #[arg(short, long, default_value = "bot")]
+    pub(crate) nickname: String,
+
+    /// Use TLS for connection
+    #[arg(long)]
+    pub(crate) tls: bool,
+
+    /// PEM client certificate to present over TLS, for servers that identify us by its
+    /// fingerprint (CertFP). Needs --tls-client-key
+    #[arg(long, requires_all = ["tls", "tls_client_key"])]
+    pub(crate) tls_client_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-client-cert
+    #[arg(long, requires = "tls_client_cert")]
+    pub(crate) tls_client_key: Option<PathBuf>,
+
+    #[arg(short, long, default_value = "false")]
+    pub(crate) leader: bool,
+
+    /// Seed for all random decisions, so a run can be replayed (random if omitted)
+    #[arg(long)]
+    pub(crate) seed: Option<u64>,
+
+    /// Stop sequence for the model, can be given multiple times (e.g. "alice -")
+    #[arg(long = "stop")]
+    pub(crate) stop: Vec<String>,
+
+    /// Append channel messages and replies to this JSONL transcript file
+    #[arg(long)]
+    pub(crate) transcript: Option<PathBuf>,
+
+    /// Rotate the transcript once it would grow past this many bytes, starting a fresh file
+    #[arg(long, requires = "transcript")]
+    pub(crate) transcript_max_bytes: Option<u64>,
+
+    /// Rotated transcript files to keep, the oldest being removed first
+    #[arg(long, default_value_t = 5)]
+    pub(crate) transcript_keep: usize,
+
+    /// Replay a JSONL transcript offline instead of connecting to IRC, printing the replies
+    #[arg(long)]
+    pub(crate) replay: Option<PathBuf>,
+
+    /// Prefix the first line of each reply with the nick of the sender being answered
+    #[arg(long)]
+    pub(crate) address_sender: bool,
+
+    /// Pick the model per turn from weighted choices instead of always using --model
+    /// (e.g. "openai/gpt-4o=0.7,anthropic/claude-3.5-sonnet=0.3")
+    #[arg(long, value_parser = parse_model_weights)]
+    pub(crate) model_weights: Option<ModelWeights>,
+
+    /// Maximum size in bytes of an outgoing IRC line, including the PRIVMSG overhead
+    #[arg(long, default_value_t = 512)]
+    pub(crate) max_line_bytes: usize,
+
+    /// Nickname allowed to run admin commands, can be given multiple times. When the server
+    /// tags messages with the sender's services account, the account is checked instead
+    #[arg(long = "admin")]
+    pub(crate) admins: Vec<String>,
+
+    /// Admin for one channel only, as `#channel=nick`, checked before the --admin list. Can
+    /// be given multiple times
+    #[arg(long = "channel-admin", value_parser = ChannelNick::parse)]
+    pub(crate) channel_admins: Vec<ChannelNick>,
+
+    /// Nickname whose channel messages are never replied to or added to the history, can be
+    /// given multiple times
+    #[arg(long = "ignore")]
+    pub(crate) ignored: Vec<String>,
+
+    /// Nickname to ignore in one channel only, as `#channel=nick`, checked before the
+    /// --ignore list. Can be given multiple times
+    #[arg(long = "channel-ignore", value_parser = ChannelNick::parse)]
+    pub(crate) channel_ignored: Vec<ChannelNick>,
+
+    /// Tell the model who is currently in the channel with a system note
+    #[arg(long)]
+    pub(crate) include_participants: bool,
+
+    /// Tell the model how many are in the channel, how busy it has been lately and how long
+    /// we've been running, with a system note refreshed every turn
+    #[arg(long)]
+    pub(crate) context_stats: bool,
+
+    /// Join multi-line replies into a single line before sending
+    #[arg(long)]
+    pub(crate) strip_newlines: bool,
+
+    /// Remove backticks from replies before sending
+    #[arg(long)]
+    pub(crate) strip_backticks: bool,
+
+    /// Convert markdown in replies to IRC formatting (bold, italics) and drop the rest
+    #[arg(long)]
+    pub(crate) strip_markdown: bool,
+
+    /// How many times to retry a request the API rejected with 429 Too Many Requests
+    #[arg(long, default_value_t = 3)]
+    pub(crate) rate_limit_retries: u32,
+
+    /// Seconds to wait before the first rate limit retry, doubled on each further retry. A
+    /// `Retry-After` the API sends with the rejection is waited out instead
+    #[arg(long, default_value_t = 5)]
+    pub(crate) rate_limit_delay: u64,
+
+    /// Longest wait in seconds between rate limit retries. A `Retry-After` asking for longer
+    /// makes us give up on the reply instead
+    #[arg(long, default_value_t = 300)]
+    pub(crate) rate_limit_max_delay: u64,
+
+    /// Only reply to messages that look like questions, others are kept as context
+    #[arg(long)]
+    pub(crate) questions_only: bool,
+
+    /// Messages shorter than this many characters, once trimmed, are kept as context
+    /// without a reply unless they mention us
+    #[arg(long)]
+    pub(crate) min_trigger_length: Option<usize>,
+
+    /// Instead of flushing each sender after they go idle, collect every channel message in
+    /// a fixed window of this many milliseconds and answer them as one turn
+    #[arg(long)]
+    pub(crate) batch_window_ms: Option<u64>,
+
+    /// Introduction sent once before the first reply to each user who mentions the bot
+    #[arg(long)]
+    pub(crate) introduction: Option<String>,
+
+    /// Ask the model for JSON turns of the form {"action": "say|emote|ignore", "text": "..."}
+    /// and act on them, falling back to plain text if a reply doesn't parse
+    #[arg(long)]
+    pub(crate) response_schema: bool,
+
+    /// Nickname of a conversation partner, can be given multiple times. When a peer leaves a
+    /// channel, replies there pause until it returns, except to messages mentioning us
+    #[arg(long = "peer")]
+    pub(crate) peers: Vec<String>,
+
+    /// Seconds to wait before reconnecting after losing the IRC connection, doubled after
+    /// each failed attempt up to five minutes
+    #[arg(long, default_value_t = 5)]
+    pub(crate) reconnect_delay: u64,
+
+    /// Reconnect if no PRIVMSG at all has been received for this many seconds
+    #[arg(long)]
+    pub(crate) reconnect_after_silence: Option<u64>,
+
+    /// Exit after the first reply instead of running until stopped
+    #[arg(long)]
+    pub(crate) once: bool,
+
+    /// Answer this message offline and print the reply instead of connecting to IRC
+    #[arg(long, conflicts_with = "replay")]
+    pub(crate) input: Option<String>,
+
+    /// Stop calling the API for a while after this many failed requests in a row
+    #[arg(long, default_value_t = 5)]
+    pub(crate) failure_threshold: u32,
+
+    /// Seconds to pause API calls once --failure-threshold is reached, before trying again
+    #[arg(long, default_value_t = 60)]
+    pub(crate) failure_cooldown: u64,
+
+    /// Whether to collect each sender's messages separately or a whole channel's together,
+    /// flushed as one turn once the sender or channel has been quiet for a second
+    #[arg(long, value_enum, default_value_t = BufferBy::Sender, conflicts_with = "batch_window_ms")]
+    pub(crate) buffer_by: BufferBy,
+
+    /// Shell command to run after each reply, with the reply and its details as a JSON object
+    /// on stdin
+    #[arg(long)]
+    pub(crate) on_reply_command: Option<String>,
+
+    /// Paste service to upload long replies to with a plain POST of the text, posting the URL
+    /// it responds with instead of the reply
+    #[arg(long)]
+    pub(crate) paste_url: Option<String>,
+
+    /// Paste replies that would take more than this many IRC lines, with --paste-url
+    #[arg(long, default_value_t = 4)]
+    pub(crate) paste_threshold: usize,
+
+    /// Answer every message on its own as soon as it arrives, without waiting for the sender
+    /// to go quiet
+    #[arg(long, conflicts_with_all = ["batch_window_ms", "buffer_by"])]
+    pub(crate) no_buffer: bool,
+
+    /// Mark ourselves away after not replying for this many seconds, until the next reply
+    #[arg(long)]
+    pub(crate) away_after: Option<u64>,
+
+    /// Away message used with --away-after
+    #[arg(long, default_value = "Away")]
+    pub(crate) away_message: String,
+
+    /// JSONL file of few-shot example messages, each {"role": "user|assistant|system",
+    /// "content": "..."}, sent before the channel history on every request
+    #[arg(long)]
+    pub(crate) examples_file: Option<PathBuf>,
+
+    /// Keep reply lines that failed to send and send them once we're back in the channel
+    #[arg(long)]
+    pub(crate) resend_failed: bool,
+
+    /// Times to try a reply line again when sending it fails. If it still doesn't go out,
+    /// the rest of the reply is held back (or kept, with --resend-failed) and we reconnect
+    #[arg(long, default_value_t = 2)]
+    pub(crate) send_retries: u32,
+
+    /// Milliseconds to wait before trying a reply line again
+    #[arg(long, default_value_t = 250)]
+    pub(crate) send_retry_delay_ms: u64,
+
+    /// Load channel history from this JSON file at startup and save it as turns change it
+    #[arg(long)]
+    pub(crate) history_file: Option<PathBuf>,
+
+    /// Seconds to collect history changes for before saving them to --history-file
+    #[arg(long, default_value_t = 5)]
+    pub(crate) history_save_interval: u64,
+
+    /// What to do when the history file can't be loaded or saved
+    #[arg(long, value_enum, default_value_t = OnHistoryError::Warn)]
+    pub(crate) on_history_error: OnHistoryError,
+
+    /// What to do when the server won't let us into a channel, because it doesn't exist, is
+    /// full, is invite only, bans us or wants a key
+    #[arg(long, value_enum, default_value_t = OnJoinError::Retry)]
+    pub(crate) on_join_error: OnJoinError,
+
+    /// Seconds before trying a rejected join again with --on-join-error retry, doubled after
+    /// each rejection up to five minutes
+    #[arg(long, default_value_t = 30)]
+    pub(crate) join_retry_delay: u64,
+
+    /// Strip a leading "nick:" or "nick," addressing us from incoming messages, counting the
+    /// message as a mention
+    #[arg(long)]
+    pub(crate) strip_nick_prefix: bool,
+
+    /// Print the model IDs the provider offers and exit
+    #[arg(long)]
+    pub(crate) list_models: bool,
+
+    /// Let the model think before replying: only what follows this delimiter in a reply is
+    /// sent, while the whole reply, reasoning included, is kept in our history
+    #[arg(long)]
+    pub(crate) hidden_delimiter: Option<String>,
+
+    /// Persona sent as the first system message. `${NAME}` is replaced with the value of the
+    /// environment variable NAME at startup
+    #[arg(long)]
+    pub(crate) system_prompt: Option<String>,
+
+    /// Read the --system-prompt from this file instead
+    #[arg(long, conflicts_with = "system_prompt")]
+    pub(crate) system_prompt_file: Option<PathBuf>,
+
+    /// Directory of per-channel personas, `rust.json` for #rust, each a JSON object with an
+    /// optional "system_prompt" and "examples" list that replace the global ones there
+    #[arg(long)]
+    pub(crate) persona_dir: Option<PathBuf>,
+
+    /// Fraction of each reconnect delay that is randomized, from 0 (wait the exact delay) to
+    /// 1 (wait anywhere up to it), so bots that dropped together don't reconnect together
+    #[arg(long, default_value_t = 1.0, value_parser = parse_fraction)]
+    pub(crate) reconnect_jitter: f64,
+
+    /// Placeholder posted to the channel (e.g. "…") when the model takes longer than
+    /// --thinking-after to reply
+    #[arg(long)]
+    pub(crate) thinking_message: Option<String>,
+
+    /// Milliseconds to wait for the model before posting the --thinking-message
+    #[arg(long, default_value_t = 3000)]
+    pub(crate) thinking_after: u64,
+
+    /// Post the --thinking-message at most once per this many seconds in a channel
+    #[arg(long, default_value_t = 60)]
+    pub(crate) thinking_interval: u64,
+
+    /// Canned reply sent when the API request fails for good, instead of staying silent
+    #[arg(long, default_value = "")]
+    pub(crate) fallback_reply: String,
+
+    /// Send a tiny throwaway request at startup to each model the channels will use, to open
+    /// the API connection and check the credentials and models before the first real turn
+    #[arg(long)]
+    pub(crate) warmup: bool,
+
+    /// System prompt used instead of --system-prompt when running as --leader, e.g. to drive
+    /// the topic while followers react. Interpolated the same way
+    #[arg(long)]
+    pub(crate) leader_prompt: Option<String>,
+
+    /// Log every command received from the server at trace level
+    #[arg(long)]
+    pub(crate) trace_protocol: bool,
+
+    /// How many turns may be answered at the same time, across channels. Turns in the same
+    /// channel are answered one after another, unless --turn-order says otherwise
+    #[arg(long, default_value_t = 1, value_parser = parse_positive_count)]
+    pub(crate) max_concurrency: usize,
+
+    /// Which turns wait for the one before them to be answered and sent, in the order they
+    /// were flushed, so a slow model doesn't interleave replies
+    #[arg(long, value_enum, default_value_t = TurnOrder::Channel)]
+    pub(crate) turn_order: TurnOrder,
+
+    /// Only reply in these channels, comma separated. Other joined channels are still
+    /// logged and kept as context, but the bot stays silent there. Replies everywhere if unset
+    #[arg(long, value_delimiter = ',')]
+    pub(crate) respond_channels: Vec<String>,
+
+    /// Only count the nickname as a mention when it stands as a word of its own ("bot:",
+    /// "@bot"), not inside another word ("robot")
+    #[arg(long)]
+    pub(crate) mention_strict: bool,
+
+    /// Prefix each chunk of a reply that spans several with a "(1/3)" style counter
+    #[arg(long)]
+    pub(crate) number_chunks: bool,
+
+    /// As the leader, try to revive a channel nobody has spoken in for this many seconds,
+    /// with a message drawn from its history. Tried once per quiet spell
+    #[arg(long, requires = "leader")]
+    pub(crate) revive_after: Option<u64>,
+
+    /// Don't reply to a turn containing this text, matched case-insensitively. The turn is
+    /// still kept as context. Can be given multiple times
+    #[arg(long)]
+    pub(crate) suppress_if_contains: Vec<String>,
+
+    /// Ask the model for this many candidate replies per turn, picked from by
+    /// --choice-strategy
+    #[arg(long, default_value_t = 1, value_parser = parse_positive_count)]
+    pub(crate) n_choices: usize,
+
+    /// How to pick among the --n-choices candidate replies
+    #[arg(long, value_enum, default_value_t = ChoiceStrategy::First)]
+    pub(crate) choice_strategy: ChoiceStrategy,
+
+    /// Model that picks the best candidate with --choice-strategy rank
+    #[arg(long, required_if_eq("choice_strategy", "rank"))]
+    pub(crate) ranking_model: Option<String>,
+
+    /// Remove our own nickname from the start of replies ("bot: hi" is sent as "hi"), for
+    /// models that copy the nick prefixes of the history
+    #[arg(long)]
+    pub(crate) strip_own_name: bool,
+
+    /// Give up and exit with status 3 after this many reconnect attempts in a row fail to
+    /// get us registered. Keeps retrying forever if unset
+    #[arg(long)]
+    pub(crate) max_reconnects: Option<u32>,
+
+    /// Treat losing the connection more than this many times within --flap-window as
+    /// flapping, and wait --flap-cooldown before trying again so the server doesn't ban us
+    #[arg(long)]
+    pub(crate) flap_max: Option<usize>,
+
+    /// Seconds over which lost connections are counted for --flap-max
+    #[arg(long, default_value_t = 600)]
+    pub(crate) flap_window: u64,
+
+    /// Seconds to wait before reconnecting once the connection is flapping
+    #[arg(long, default_value_t = 1800)]
+    pub(crate) flap_cooldown: u64,
+
+    /// Seconds without hearing from the server before we PING it. Lower it to keep idle
+    /// connections alive through NATs and firewalls that drop them sooner
+    #[arg(long, default_value_t = 180, value_parser = clap::value_parser!(u32).range(1..))]
+    pub(crate) ping_interval: u32,
+
+    /// Seconds to wait for the server to answer our PING before reconnecting
+    #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(u32).range(1..))]
+    pub(crate) ping_timeout: u32,
+
+    /// Append replies to this file, in the same "#channel <nick> text" form as offline runs
+    /// print them. Used instead of stdout when offline, and alongside IRC otherwise
+    #[arg(long)]
+    pub(crate) output_file: Option<PathBuf>,
+
+    /// Text put in front of every line we send in a reply (e.g. "[gpt4]"), to tell bots
+    /// apart in a busy channel. Counted against the line limit
+    #[arg(long)]
+    pub(crate) output_prefix: Option<String>,
+
+    /// Seconds a human has the floor after speaking: any message from a nick that isn't a
+    /// --peer holds back replies to turns with only peers in them, so bots don't talk over
+    /// people
+    #[arg(long)]
+    pub(crate) human_floor: Option<u64>,
+
+    /// Referee the channel instead of chatting: after each turn, tell the next --peer in
+    /// rotation that it's their turn with "@nick your turn"
+    #[arg(long, requires = "peers", conflicts_with = "referee_nick")]
+    pub(crate) referee: bool,
+
+    /// Only reply when this referee's directive names us, taking turns instead of all at
+    /// once. Directives are left out of the history
+    #[arg(long)]
+    pub(crate) referee_nick: Option<String>,
+
+    /// Listen on this Unix socket for line-delimited JSON control commands: reset, pause,
+    /// resume, set-model, inject-message and get-stats
+    #[arg(long)]
+    pub(crate) control_socket: Option<PathBuf>,
+
+    /// Drop channel messages that weren't valid UTF-8, with a warning. By default the IRC
+    /// client's decoding has already replaced invalid bytes with U+FFFD, and such messages
+    /// are buffered with the replacement characters in them
+    #[arg(long)]
+    pub(crate) strict_utf8: bool,
+
+    /// Merge consecutive messages with the same role into one before sending them, for
+    /// providers that want roles to alternate or do better with merged turns
+    #[arg(long)]
+    pub(crate) merge_same_role: bool,
+
+    /// What merged messages are joined with under --merge-same-role
+    #[arg(long, default_value = "\n")]
+    pub(crate) turn_separator: String,
+
+    /// When a ban or quiet stops our messages getting through a channel, part and rejoin it
+    /// once in case it was tied to how we were connected
+    #[arg(long)]
+    pub(crate) rejoin_when_muted: bool,
+
+    /// Seconds to remember every line said in a channel, by anyone including us. A turn made
+    /// only of lines repeated within that window is kept as context without a reply, which
+    /// breaks bots echoing each other
+    #[arg(long)]
+    pub(crate) dedup_window: Option<u64>,
+
+    /// Milliseconds to wait between JOINs, so joining many channels doesn't trip the server's
+    /// join-flood protection
+    #[arg(long, default_value_t = 0)]
+    pub(crate) join_delay_ms: u64,
+
+    /// Most characters a flushed turn's messages may add up to. Beyond that the message that
+    /// goes over is cut off with a marker and any after it are dropped, so many short lines
+    /// can't add up to a flood
+    #[arg(long)]
+    pub(crate) max_combined_chars: Option<usize>,
+
+    /// Reply somewhere other than where a turn was said, given as
+    /// `SOURCE[,from=NICK][,contains=TEXT]>TARGET` where SOURCE is a channel or `*` for any and
+    /// TARGET a channel or nick. The first matching route is used. Can be given multiple times
+    #[arg(long = "reply-route", value_parser = ReplyRoute::parse)]
+    pub(crate) reply_routes: Vec<ReplyRoute>,
+
+    /// Fold senders' nicks the way IRC compares them (lowercase, with []\~ as {}|^) before
+    /// buffering, so "Alice" and "alice" share a buffer and a name in the history
+    #[arg(long)]
+    pub(crate) fold_nicks: bool,
+
+    /// When finishing on our own, as with --once or offline runs, ask the model to sum up
+    /// each channel's discussion and post the summary there
+    #[arg(long)]
+    pub(crate) summary_on_exit: bool,
+
+    /// Instructions for the --summary-on-exit and --rolling-summary summaries
+    #[arg(long, default_value = DEFAULT_SUMMARY_PROMPT)]
+    pub(crate) summary_prompt: String,
+
+    /// Also append the --summary-on-exit summaries to this file
+    #[arg(long, requires = "summary_on_exit")]
+    pub(crate) summary_file: Option<PathBuf>,
+
+    /// Send only this many of the latest history entries word for word, and thin out older
+    /// ones more the further back they go
+    #[arg(long)]
+    pub(crate) decay_after: Option<usize>,
+
+    /// How fast --decay-after thins out older history: each --decay-after entries further
+    /// back keep one in this many more, so 2 keeps every 2nd, then every 4th, and so on
+    #[arg(long, default_value_t = 2.0)]
+    pub(crate) decay_factor: f64,
+
+    /// Answer "!whoami" from anyone with the model we're on, where it's served from and how
+    /// long we've been up
+    #[arg(long)]
+    pub(crate) whoami: bool,
+
+    /// What to do with messages quoting one of our recent replies back, which only muddle
+    /// the context: tag them as quotes, or skip them. Left as they are if unset
+    #[arg(long, value_enum)]
+    pub(crate) quoted_replies: Option<QuotedReplies>,
+
+    /// How much of a message, or of our reply, has to match for --quoted-replies to count it
+    /// as a quote, from 0 to 1
+    #[arg(long, default_value_t = 0.8, value_parser = parse_fraction)]
+    pub(crate) quote_similarity: f64,
+
+    /// Seconds after joining a channel to only listen, keeping what's said as context, before
+    /// replying there
+    #[arg(long)]
+    pub(crate) startup_grace: Option<u64>,
+
+    /// Directory the !snapshot admin command and the control socket's snapshot write to
+    #[arg(long, default_value = ".")]
+    pub(crate) snapshot_dir: PathBuf,
+
+    /// Treat a reply containing this text, matched case-insensitively, as the model refusing
+    /// to take part. Can be given multiple times
+    #[arg(long)]
+    pub(crate) refusal_phrase: Vec<String>,
+
+    /// Also ask this model whether each reply is a refusal
+    #[arg(long)]
+    pub(crate) refusal_model: Option<String>,
+
+    /// What to do with a refusal found by --refusal-phrase or --refusal-model, which would
+    /// otherwise become context that derails the conversation
+    #[arg(long, value_enum, default_value_t = OnRefusal::Skip)]
+    pub(crate) on_refusal: OnRefusal,
+
+    /// System note added when asking again with --on-refusal retry
+    #[arg(long, default_value = DEFAULT_REFUSAL_REFRAME)]
+    pub(crate) refusal_reframe: String,
+
+    /// Line sent instead of a refusal with --on-refusal substitute
+    #[arg(long, default_value = DEFAULT_REFUSAL_SUBSTITUTE)]
+    pub(crate) refusal_substitute: String,
+
+    /// Every this many turns in a channel, sum up the part of its history older than the last
+    /// --rolling-summary-window entries with the --summary-prompt. The summary is pinned as a
+    /// system note and sent in place of the entries it covers
+    #[arg(long, value_parser = parse_positive_count)]
+    pub(crate) rolling_summary: Option<usize>,
+
+    /// Latest history entries always sent as they are with --rolling-summary
+    #[arg(long, default_value_t = 20)]
+    pub(crate) rolling_summary_window: usize,
+
+    /// Most reply lines to send to a target in a stretch of seconds, as `[TARGET=]LINES/SECS`
+    /// (e.g. `4/10` or `#busy=2/10`). One without a target applies wherever no other does.
+    /// A limited target's lines wait in a queue of their own, so a backed-up channel doesn't
+    /// hold up replies elsewhere. Can be given multiple times
+    #[arg(long = "send-rate", value_parser = SendRate::parse)]
+    pub(crate) send_rates: Vec<SendRate>,
+
+    /// Check the channels, the files named by other flags, the variables the prompts use and
+    /// the API key, then exit without connecting anywhere. Exits non-zero on any problem
+    #[arg(long)]
+    pub(crate) check_config: bool,
+
+    /// What to do when a --rolling-summary is due while the last one is still being made, as
+    /// happens in busy channels
+    #[arg(long, value_enum, default_value_t = SummaryBacklog::Skip)]
+    pub(crate) on_summary_backlog: SummaryBacklog,
+}
+
+/// Which turns are answered strictly one after another, under --max-concurrency
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum TurnOrder {
+    /// Turns in the same channel
+    Channel,
+    /// Turns from the same sender in a channel, while other senders' turns go ahead
+    Sender,
+    /// None, so replies in a channel can overlap
+    None,
+}
+
+/// What to do when the model refuses to reply
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum OnRefusal {
+    /// Ask once more with --refusal-reframe added, skipping the turn if it still refuses
+    Retry,
+    /// Keep the turn as context without replying
+    Skip,
+    /// Send --refusal-substitute instead
+    Substitute,
+}
+
+/// What to do when a channel's rolling summary falls behind
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SummaryBacklog {
+    /// Let this summary go, trying again --rolling-summary turns later
+    Skip,
+    /// Send only the last --rolling-summary-window entries until the summary is done
+    Truncate,
+}
+
+/// How one reply is made of several candidates
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ChoiceStrategy {
+    /// The first candidate
+    First,
+    /// A candidate picked with the shared PRNG, so seeded runs pick the same
+    Random,
+    /// The candidate the --ranking-model thinks is best
+    Rank,
+    /// Every candidate, each on its own line prefixed with its number
+    All,
+}
+
+pub(crate) const DEFAULT_SUMMARY_PROMPT: &str = "Summarize the discussion so far: the main points, where the participants agreed and disagreed, and what was left open.";
+
+pub(crate) const DEFAULT_REFUSAL_REFRAME: &str = "This is an open discussion among participants and nothing is being asked of you beyond taking part. Reply to the conversation in your own voice.";
+
+pub(crate) const DEFAULT_REFUSAL_SUBSTITUTE: &str = "I'll sit this one out.";
+
+pub(crate) const DEFAULT_CHANNEL: &str = "#chat_0098";
+
+/// A channel to join, with optional per-channel overrides
+#[derive(Clone, Debug)]
+pub(crate) struct ChannelConfig {
+    pub(crate) name: String,
+    /// Model to use in this channel instead of --model
+    pub(crate) model: Option<String>,
+    /// Channel key needed to join
+    pub(crate) key: Option<String>,
+}
+
+impl ChannelConfig {
+    /// Parses a `#channel[,model[,key]]` line, where empty fields are left unset
+    pub(crate) fn parse(line: &str) -> Result<Self, String> {
+        let mut fields = line.split(',').map(str::trim);
+        let name = fields.next().unwrap_or_default().to_string();
+        if !is_channel_name(&name) {
+            return Err(format!("'{}' is not a channel name", name));
+        }
+
+        let mut optional = || fields.next().filter(|f| !f.is_empty()).map(str::to_string);
+        let model = optional();
+        let key = optional();
+
+        Ok(Self { name, model, key })
+    }
+}
+
+/// Reads channel configs from a file, one per line, skipping blank lines
+pub(crate) fn read_channels_file(path: &Path) -> Result<Vec<ChannelConfig>, ChatroomError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut channels = Vec::new();
+
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let channel = ChannelConfig::parse(&line)
+            .map_err(|e| format!("{}:{}: {}", path.display(), number + 1, e))?;
+        channels.push(channel);
+    }
+
+    Ok(channels)
+}
+
+pub(crate) fn is_channel_name(target: &str) -> bool {
+    target.starts_with(['#', '&'])
+}
+
+/// Key for per-channel maps, as IRC channel names are case-insensitive
+pub(crate) fn channel_key(channel: &str) -> String {
+    channel.to_ascii_lowercase()
+}
+
+/// Weighted set of models to sample from on each turn
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ModelWeights {
+    pub(crate) models: Vec<String>,
+    weights: Vec<f64>,
+    #[serde(skip)]
+    distribution: WeightedIndex<f64>,
+}
+
+impl ModelWeights {
+    pub(crate) fn sample<R: Rng>(&self, rng: &mut R) -> &str {
+        &self.models[self.distribution.sample(rng)]
+    }
+}
+
+/// Parses a count of at least one
+fn parse_positive_count(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(count) => Ok(count),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+/// Sends replies to the turns it matches somewhere other than where they were said, such as
+/// from a watched public channel to a private one
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ReplyRoute {
+    /// Keyed by `channel_key`, or None for any channel
+    source: Option<String>,
+    /// Nick of someone who spoke in the turn, folded by `fold_nick`
+    from: Option<String>,
+    /// Lowercased text said in the turn
+    contains: Option<String>,
+    pub(crate) target: String,
+}
+
+impl ReplyRoute {
+    /// Parses `SOURCE[,from=NICK][,contains=TEXT]>TARGET`
+    pub(crate) fn parse(rule: &str) -> Result<Self, String> {
+        let (conditions, target) = rule
+            .rsplit_once('>')
+            .ok_or_else(|| format!("'{}' has no '>' before the target", rule))?;
+        let target = target.trim();
+        if target.is_empty() {
+            return Err(format!("'{}' has no target", rule));
+        }
+
+        let mut conditions = conditions.split(',').map(str::trim);
+        let source = match conditions.next().unwrap_or_default() {
+            "*" => None,
+            source if is_channel_name(source) => Some(channel_key(source)),
+            source => return Err(format!("'{}' is not a channel name or *", source)),
+        };
+        let mut route = ReplyRoute {
+            source,
+            from: None,
+            contains: None,
+            target: target.to_string(),
+        };
+        for condition in conditions {
+            match condition.split_once('=') {
+                Some(("from", nick)) => route.from = Some(fold_nick(nick)),
+                Some(("contains", text)) => route.contains = Some(text.to_lowercase()),
+                _ => return Err(format!("unknown condition '{}'", condition)),
+            }
+        }
+        Ok(route)
+    }
+
+    pub(crate) fn matches(&self, turn: &Turn) -> bool {
+        let channel = channel_key(&turn.channel);
+        let source = self.source.as_ref().is_none_or(|source| *source == channel);
+        let from = self.from.as_ref().is_none_or(|from| {
+            turn.messages.iter().any(|(sender, _)| same_nick(sender, from))
+        });
+        let contains = self.contains.as_ref().is_none_or(|text| {
+            turn.messages.iter().any(|(_, msg)| msg.to_lowercase().contains(text.as_str()))
+        });
+        source && from && contains
+    }
+}
+
+/// A limit on how fast reply lines go to a target
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct SendRate {
+    /// Keyed by `channel_key`, or None for any target
+    pub(crate) target: Option<String>,
+    pub(crate) lines: usize,
+    pub(crate) per_secs: u64,
+}
+
+impl SendRate {
+    /// Parses `[TARGET=]LINES/SECS`
+    pub(crate) fn parse(rule: &str) -> Result<Self, String> {
+        let (target, rate) = match rule.split_once('=') {
+            Some((target, rate)) => (Some(channel_key(target.trim())), rate),
+            None => (None, rule),
+        };
+        let (lines, per_secs) = rate
+            .split_once('/')
+            .ok_or_else(|| format!("'{}' is not LINES/SECS", rate))?;
+        let lines = parse_positive_count(lines.trim())?;
+        let per_secs = per_secs.trim().parse().map_err(|e| format!("{}", e))?;
+        Ok(SendRate {
+            target,
+            lines,
+            per_secs,
+        })
+    }
+}
+
+/// A nick listed for one channel
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct ChannelNick {
+    /// Keyed by `channel_key`
+    pub(crate) channel: String,
+    pub(crate) nick: String,
+}
+
+impl ChannelNick {
+    /// Parses `#channel=nick`
+    pub(crate) fn parse(entry: &str) -> Result<Self, String> {
+        let (channel, nick) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("'{}' is not #channel=nick", entry))?;
+        let (channel, nick) = (channel.trim(), nick.trim());
+        if !is_channel_name(channel) {
+            return Err(format!("'{}' is not a channel name", channel));
+        }
+        if nick.is_empty() {
+            return Err(format!("'{}' names no nick", entry));
+        }
+        Ok(ChannelNick {
+            channel: channel_key(channel),
+            nick: nick.to_string(),
+        })
+    }
+}
+
+/// Whether `nick` is on the list for `channel`, or else on the global list
+pub(crate) fn is_listed(
+    global: &[String],
+    scoped: &[ChannelNick],
+    channel: Option<&str>,
+    nick: &str,
+) -> bool {
+    let channel = channel.map(channel_key);
+    scoped
+        .iter()
+        .filter(|entry| Some(&entry.channel) == channel.as_ref())
+        .any(|entry| same_nick(&entry.nick, nick))
+        || global.iter().any(|listed| same_nick(listed, nick))
+}
+
+/// Parses a number from 0 to 1
+pub(crate) fn parse_fraction(value: &str) -> Result<f64, String> {
+    let fraction: f64 = value.parse().map_err(|e| format!("{}", e))?;
+    if !(0.0..=1.0).contains(&fraction) {
+        return Err(format!("{} is not between 0 and 1", fraction));
+    }
+    Ok(fraction)
+}
+
+/// Parses a `model=weight,model=weight` spec
+fn parse_model_weights(spec: &str) -> Result<ModelWeights, String> {
+    let mut models = Vec::new();
+    let mut weights = Vec::new();
+
+    for part in spec.split(',') {
+        let (model, weight) = part
+            .trim()
+            .rsplit_once('=')
+            .ok_or_else(|| format!("expected model=weight, got '{}'", part))?;
+        let weight: f64 = weight
+            .trim()
+            .parse()
+            .map_err(|e| format!("invalid weight for '{}': {}", model, e))?;
+        models.push(model.trim().to_string());
+        weights.push(weight);
+    }
+
+    let distribution = WeightedIndex::new(&weights).map_err(|e| e.to_string())?;
+    Ok(ModelWeights {
+        models,
+        weights,
+        distribution,
+    })
+}
+
+/// Replaces each `${NAME}` in the text with what `lookup` gives for NAME, leaving references
+/// to unknown names as they are
+pub(crate) fn interpolate_env(text: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start + 2..].find('}') else {
+            break;
+        };
+        result.push_str(&rest[..start]);
+        let name = &rest[start + 2..start + 2 + len];
+        let reference = &rest[start..start + 2 + len + 1];
+        match lookup(name) {
+            Some(value) => result.push_str(&value),
+            None => {
+                warn!("{} is not set, leaving it in the system prompt", name);
+                result.push_str(reference);
+            }
+        }
+        rest = &rest[start + reference.len()..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// Whether an example message has a role the API accepts
+fn is_example_role(role: &str) -> bool {
+    [mini_openai::ROLE_SYSTEM, mini_openai::ROLE_USER, mini_openai::ROLE_ASSISTANT].contains(&role)
+}
+
+/// Reads few-shot example messages from a JSONL file, one message per line
+pub(crate) fn read_examples_file(path: &Path) -> Result<Vec<mini_openai::Message>, ChatroomError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut examples = Vec::new();
+
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let example: mini_openai::Message = serde_json::from_str(&line)?;
+        if !is_example_role(&example.role) {
+            return Err(format!("line {}: unknown role {:?}", number + 1, example.role).into());
+        }
+        examples.push(example);
+    }
+
+    Ok(examples)
+}
+
+/// A channel's own persona from --persona-dir, replacing the global system prompt and
+/// examples where it sets them
+#[derive(Deserialize, Default)]
+pub(crate) struct Persona {
+    pub(crate) system_prompt: Option<String>,
+    pub(crate) examples: Option<Vec<mini_openai::Message>>,
+}
+
+/// Reads the persona of each channel that has a file in the directory, keyed by
+/// `channel_key`. Channels without one keep the global persona
+pub(crate) fn read_personas(
+    dir: &Path,
+    channels: &[ChannelConfig],
+) -> Result<HashMap<String, Persona>, ChatroomError> {
+    let mut personas = HashMap::new();
+    for channel in channels {
+        let key = channel_key(&channel.name);
+        let path = dir.join(format!("{}.json", key.trim_start_matches(['#', '&'])));
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(format!("{}: {}", path.display(), e).into()),
+        };
+        let mut persona: Persona =
+            serde_json::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut examples = persona.examples.iter().flatten();
+        if let Some(example) = examples.find(|example| !is_example_role(&example.role)) {
+            return Err(format!("{}: unknown role {:?}", path.display(), example.role).into());
+        }
+        persona.system_prompt = persona
+            .system_prompt
+            .map(|prompt| interpolate_env(&prompt, |name| env::var(name).ok()));
+        personas.insert(key, persona);
+    }
+    Ok(personas)
+}
+
+/// What to do when the history file can't be loaded or saved
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum OnHistoryError {
+    /// Carry on without a word, starting with empty history if loading failed
+    Ignore,
+    /// Log a warning and carry on, starting with empty history if loading failed
+    Warn,
+    /// Refuse to start if loading failed, and log saving failures as errors
+    Fatal,
+}
+
+/// What to do when the server won't let us into a channel
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum OnJoinError {
+    /// Try joining again later, waiting longer after each rejection
+    Retry,
+    /// Carry on in the other channels
+    Skip,
+    /// Stop with an error
+    Exit,
+}
+
+/// What to do with a message quoting one of our replies back
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum QuotedReplies {
+    /// Keep it, marked as quoting us so the model doesn't take it as new
+    Tag,
+    /// Leave it out of the history and don't answer it
+    Skip,
+}
+
+/// What incoming messages are grouped by before they are answered
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum BufferBy {
+    /// Each sender's messages in a channel are answered on their own
+    Sender,
+    /// Everything said in a channel is answered together
+    Channel,
+}
+
+/// Flags snapshots record. Paths, URLs and commands are left out, as they can point at key
+/// material or local files or carry credentials, and so is anything not listed here
+const SNAPSHOT_CONFIG: &[&str] = &[
+    "model", "server", "port", "channels", "nickname", "tls", "leader", "seed", "stop",
+    "transcript_max_bytes", "transcript_keep", "address_sender", "model_weights",
+    "max_line_bytes", "admins", "channel_admins", "ignored", "channel_ignored",
+    "include_participants", "context_stats", "strip_newlines", "strip_backticks",
+    "strip_markdown", "rate_limit_retries", "rate_limit_delay", "rate_limit_max_delay",
+    "questions_only", "min_trigger_length", "batch_window_ms", "introduction",
+    "response_schema", "peers", "reconnect_delay", "reconnect_after_silence", "once",
+    "failure_threshold", "failure_cooldown", "buffer_by", "paste_threshold", "no_buffer",
+    "away_after", "away_message", "resend_failed", "send_retries", "send_retry_delay_ms",
+    "history_save_interval", "on_history_error", "on_join_error", "join_retry_delay",
+    "strip_nick_prefix", "hidden_delimiter", "system_prompt", "reconnect_jitter",
+    "thinking_message", "thinking_after", "thinking_interval", "fallback_reply", "warmup",
+    "leader_prompt", "trace_protocol", "max_concurrency", "turn_order", "respond_channels",
+    "mention_strict", "number_chunks", "revive_after", "suppress_if_contains", "n_choices",
+    "choice_strategy", "ranking_model", "strip_own_name", "max_reconnects", "flap_max",
+    "flap_window", "flap_cooldown", "ping_interval", "ping_timeout", "output_prefix",
+    "human_floor", "referee", "referee_nick", "strict_utf8", "merge_same_role",
+    "turn_separator", "rejoin_when_muted", "dedup_window", "join_delay_ms",
+    "max_combined_chars", "reply_routes", "fold_nicks", "summary_on_exit", "summary_prompt",
+    "decay_after", "decay_factor", "quoted_replies", "quote_similarity", "startup_grace",
+    "refusal_phrase", "refusal_model", "on_refusal", "refusal_reframe", "refusal_substitute",
+    "rolling_summary", "rolling_summary_window", "send_rates", "on_summary_backlog",
+];
+
+/// Our arguments as snapshots record them, only those in `SNAPSHOT_CONFIG` and without
+/// channel keys
+pub(crate) fn snapshot_config(args: &Args) -> serde_json::Value {
+    let mut all = serde_json::to_value(args).unwrap_or_default();
+    let mut config = serde_json::Map::new();
+    for field in SNAPSHOT_CONFIG {
+        if let Some(value) = all.get_mut(*field) {
+            config.insert(field.to_string(), value.take());
+        }
+    }
+    if let Some(channels) = config.get_mut("channels").and_then(|c| c.as_array_mut()) {
+        for channel in channels {
+            if let Some(line) = channel.as_str() {
+                *channel = line.splitn(3, ',').take(2).collect::<Vec<_>>().join(",").into();
+            }
+        }
+    }
+    config.into()
+}
+
+/// What --check-config finds wrong, looking variables up with `lookup`. Nothing here opens a
+/// connection or writes anything but the certificate bundle it removes again
+pub(crate) fn config_problems(
+    args: &Args,
+    custom_backend: bool,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let mut channels = Vec::new();
+    for line in &args.channels {
+        match ChannelConfig::parse(line) {
+            Ok(channel) => channels.push(channel),
+            Err(e) => problems.push(format!("--channel: {}", e)),
+        }
+    }
+    if let Some(path) = &args.channels_from_file {
+        match read_channels_file(path) {
+            Ok(more) => channels.extend(more),
+            Err(e) => problems.push(format!("--channels-from-file {}: {}", path.display(), e)),
+        }
+    }
+
+    // Prompt variables left unset would reach the model as they are written
+    let mut prompts: Vec<(&str, String)> = Vec::new();
+    prompts.extend(args.system_prompt.clone().map(|prompt| ("--system-prompt", prompt)));
+    prompts.extend(args.leader_prompt.clone().map(|prompt| ("--leader-prompt", prompt)));
+    if let Some(path) = &args.system_prompt_file {
+        match std::fs::read_to_string(path) {
+            Ok(prompt) => prompts.push(("--system-prompt-file", prompt)),
+            Err(e) => problems.push(format!("--system-prompt-file {}: {}", path.display(), e)),
+        }
+    }
+    for (flag, prompt) in prompts {
+        let unset = std::cell::RefCell::new(Vec::new());
+        interpolate_env(&prompt, |name| {
+            let value = lookup(name);
+            if value.is_none() {
+                unset.borrow_mut().push(name.to_string());
+            }
+            value
+        });
+        for name in unset.into_inner() {
+            problems.push(format!("{} uses ${{{}}}, which is not set", flag, name));
+        }
+    }
+
+    if let Some(path) = &args.examples_file {
+        if let Err(e) = read_examples_file(path) {
+            problems.push(format!("--examples-file {}: {}", path.display(), e));
+        }
+    }
+    if let Some(dir) = &args.persona_dir {
+        if let Err(e) = read_personas(dir, &channels) {
+            problems.push(format!("--persona-dir {}: {}", dir.display(), e));
+        }
+    }
+    if let Some(path) = &args.replay {
+        if let Err(e) = read_transcript(path) {
+            problems.push(format!("--replay {}: {}", path.display(), e));
+        }
+    }
+    if let Some(path) = args.history_file.as_ref().filter(|path| path.exists()) {
+        if let Err(e) = History::new(&channels).load(path) {
+            problems.push(format!("--history-file {}: {}", path.display(), e));
+        }
+    }
+    if let (Some(cert), Some(key)) = (&args.tls_client_cert, &args.tls_client_key) {
+        if let Err(e) = ClientIdentity::bundle(cert, key) {
+            problems.push(format!("--tls-client-cert {}: {}", cert.display(), e));
+        }
+    }
+
+    // Files we'd create need a directory to go in
+    let created = [
+        ("--output-file", &args.output_file),
+        ("--transcript", &args.transcript),
+        ("--summary-file", &args.summary_file),
+        ("--control-socket", &args.control_socket),
+    ];
+    for (flag, path) in created {
+        let Some(path) = path else {
+            continue;
+        };
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        if dir.is_some_and(|dir| !dir.is_dir()) {
+            problems.push(format!("{} {}: no such directory", flag, path.display()));
+        }
+    }
+    if !args.snapshot_dir.is_dir() {
+        problems.push(format!("--snapshot-dir {}: no such directory", args.snapshot_dir.display()));
+    }
+
+    if !custom_backend && lookup("OPENROUTER_API_KEY").is_none() {
+        problems.push("OPENROUTER_API_KEY is not set".to_string());
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_channel_lines() {
+        let plain = ChannelConfig::parse("#rust").unwrap();
+        assert_eq!((plain.name.as_str(), plain.model, plain.key), ("#rust", None, None));
+
+        let full = ChannelConfig::parse("#ops, openai/gpt-4o , secret").unwrap();
+        assert_eq!(full.model.as_deref(), Some("openai/gpt-4o"));
+        assert_eq!(full.key.as_deref(), Some("secret"));
+
+        let key_only = ChannelConfig::parse("#ops,,secret").unwrap();
+        assert_eq!((key_only.model, key_only.key.as_deref()), (None, Some("secret")));
+
+        assert!(ChannelConfig::parse("rust").is_err());
+    }
+
+    #[test]
+    fn setup_failures_are_told_apart() {
+        let path = env::temp_dir().join(format!("channels-{}.txt", std::process::id()));
+        std::fs::write(&path, "#rust\nrust\n").unwrap();
+        let bad_line = read_channels_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(bad_line, Err(ChatroomError::Config(reason)) if reason.ends_with(
+            "2: 'rust' is not a channel name"
+        )));
+        assert!(matches!(read_channels_file(&path), Err(ChatroomError::Io(_))));
+    }
+
+    #[test]
+    fn interpolates_environment_variables() {
+        let lookup = |name: &str| (name == "BOT_NAME").then(|| "ada".to_string());
+        assert_eq!(interpolate_env("You are ${BOT_NAME}.", lookup), "You are ada.");
+        assert_eq!(interpolate_env("${BOT_NAME}${BOT_NAME}", lookup), "adaada");
+        assert_eq!(interpolate_env("In ${CHANNEL} now", lookup), "In ${CHANNEL} now");
+        assert_eq!(interpolate_env("Cost: $5, ${unclosed", lookup), "Cost: $5, ${unclosed");
+    }
+
+    #[test]
+    fn parses_reply_routes() {
+        let route = ReplyRoute::parse("#Public, from=Alice, contains=Outage > #ops").unwrap();
+        assert_eq!(route.source.as_deref(), Some("#public"));
+        assert_eq!(route.from.as_deref(), Some("alice"));
+        assert_eq!(route.contains.as_deref(), Some("outage"));
+        assert_eq!(route.target, "#ops");
+        assert!(ReplyRoute::parse("*>carol").unwrap().source.is_none());
+        assert!(ReplyRoute::parse("public>#ops").is_err());
+        assert!(ReplyRoute::parse("#public,to=bob>#ops").is_err());
+        assert!(ReplyRoute::parse("#public").is_err());
+    }
+
+    #[test]
+    fn checks_the_config_without_connecting() {
+        let args = Args::try_parse_from([
+            "chatroom",
+            "--model",
+            "mock",
+            "-c",
+            "rust",
+            "--system-prompt",
+            "You are ${ROLE} in ${PLACE}",
+            "--examples-file",
+            "/nonexistent/ex.jsonl",
+            "--output-file",
+            "/nonexistent/out.txt",
+        ])
+        .unwrap();
+        let lookup = |name: &str| (name == "ROLE").then(|| "a critic".to_string());
+        assert_eq!(
+            config_problems(&args, false, lookup),
+            [
+                "--channel: 'rust' is not a channel name",
+                "--system-prompt uses ${PLACE}, which is not set",
+                "--examples-file /nonexistent/ex.jsonl: No such file or directory (os error 2)",
+                "--output-file /nonexistent/out.txt: no such directory",
+                "OPENROUTER_API_KEY is not set",
+            ]
+        );
+
+        let args = Args::try_parse_from(["chatroom", "--model", "mock", "-c", "#rust"]).unwrap();
+        assert!(config_problems(&args, true, |_| None).is_empty());
+    }
+
+    #[test]
+    fn channel_lists_are_checked_before_global_ones() {
+        assert!(ChannelNick::parse("rust=bob").is_err());
+        assert!(ChannelNick::parse("#rust").is_err());
+        assert!(ChannelNick::parse("#rust= ").is_err());
+
+        let args = Args::try_parse_from([
+            "chatroom",
+            "--model",
+            "mock",
+            "--ignore",
+            "spambot",
+            "--channel-ignore",
+            "#Rust=[troll]",
+        ])
+        .unwrap();
+        let ignored = |channel, nick| {
+            is_listed(&args.ignored, &args.channel_ignored, Some(channel), nick)
+        };
+        assert!(ignored("#rust", "{TROLL}"));
+        assert!(!ignored("#go", "[troll]"));
+        assert!(ignored("#go", "SpamBot"));
+        assert!(!ignored("#rust", "alice"));
+    }
+
+    #[test]
+    fn snapshots_record_only_listed_flags() {
+        let args = Args::try_parse_from(["chatroom", "--model", "mock"]).unwrap();
+        let all = serde_json::to_value(&args).unwrap();
+        for field in SNAPSHOT_CONFIG {
+            assert!(all.get(*field).is_some(), "{} isn't a flag", field);
+        }
+        let config = snapshot_config(&args);
+        assert_eq!(config.as_object().unwrap().len(), SNAPSHOT_CONFIG.len());
+    }
+}
@@ -0,0 +1,2072 @@
+//! The IRC connection: connecting, joining, reading messages and sending replies
+
+use futures::*;
+use irc::client::prelude::*;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    env,
+    error::Error,
+    fs::{File, OpenOptions},
+    io::Write,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, Arc, RwLock},
+};
+use tokio::sync::{Mutex, Notify, Semaphore, mpsc};
+use tokio::task::JoinSet;
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, error, info, trace, warn};
+use crate::{
+    config::*, control::*, history::*, processor::*, provider::*, reply::*, turns::*,
+    ChatroomError, now_ms,
+};
+
+/// The value of an IRCv3 message tag, if the server sent it
+fn message_tag<'a>(message: &'a Message, name: &str) -> Option<&'a str> {
+    let tags = message.tags.as_ref()?;
+    tags.iter().find(|tag| tag.0 == name)?.1.as_deref()
+}
+
+/// Parses an IRCv3 `time` tag (e.g. "2011-10-19T16:40:51.620Z") into milliseconds since the
+/// Unix epoch
+fn parse_server_time(time: &str) -> Option<u64> {
+    let (date, clock) = time.strip_suffix('Z')?.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let (clock, fraction) = clock.split_once('.').unwrap_or((clock, "0"));
+    let mut clock = clock.splitn(3, ':').map(|part| part.parse::<i64>().ok());
+    let (hour, minute, second) = (clock.next()??, clock.next()??, clock.next()??);
+    let millis: i64 = format!("{:0<3}", fraction).get(..3)?.parse().ok()?;
+    if !(1..=12).contains(&month) {
+        return None;
+    }
+
+    // Days from the epoch to the civil date, counting years from March so leap days come last
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let seconds = days * 86_400 + hour * 3_600 + minute * 60 + second;
+    u64::try_from(seconds * 1_000 + millis).ok()
+}
+
+/// How many lines a target's send queue holds before new ones are dropped
+const SEND_QUEUE_CAPACITY: usize = 64;
+
+/// Queues for reply lines to targets with a --send-rate, each drained at its rate by a task
+/// of its own
+#[derive(Default)]
+pub(crate) struct SendQueues {
+    rates: Vec<SendRate>,
+    /// Keyed by `channel_key`
+    queues: std::sync::Mutex<HashMap<String, mpsc::Sender<UnsentLine>>>,
+}
+
+impl SendQueues {
+    fn new(rates: Vec<SendRate>) -> Self {
+        Self {
+            rates,
+            queues: std::sync::Mutex::default(),
+        }
+    }
+
+    /// The target's own rate, or else the one for any target
+    pub(crate) fn rate(&self, target: &str) -> Option<&SendRate> {
+        let target = channel_key(target);
+        self.rates
+            .iter()
+            .find(|rate| rate.target.as_ref() == Some(&target))
+            .or_else(|| self.rates.iter().find(|rate| rate.target.is_none()))
+    }
+
+    /// Queues lines for a target with a rate, starting its queue the first time. Lines that
+    /// don't fit in a full queue are dropped
+    pub(crate) fn enqueue(
+        &self,
+        processor: &Processor,
+        target: &str,
+        rate: &SendRate,
+        lines: Vec<UnsentLine>,
+    ) {
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(channel_key(target)).or_insert_with(|| {
+            let (queue, lines) = mpsc::channel(SEND_QUEUE_CAPACITY);
+            tokio::spawn(drain_send_queue(
+                lines,
+                rate.clone(),
+                processor.output.clone(),
+                Arc::clone(&processor.unsent),
+                processor.send.clone(),
+                Arc::clone(&processor.send_failed),
+            ));
+            queue
+        });
+        for line in lines {
+            match queue.try_send(line) {
+                Ok(()) => {}
+                Err(mpsc::error::TrySendError::Full(line)) => {
+                    warn!("The send queue for {} is full, dropped: {}", target, line.text);
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => {
+                    error!("The send queue for {} is gone", target);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Sends a target's queued reply lines, no more than `rate.lines` in any `rate.per_secs`. Lines
+/// that fail to send are held back along with everything queued behind them
+async fn drain_send_queue(
+    mut lines: mpsc::Receiver<UnsentLine>,
+    rate: SendRate,
+    output: Output,
+    unsent: Unsent,
+    settings: SendSettings,
+    send_failed: Arc<Notify>,
+) {
+    let per = Duration::from_secs(rate.per_secs);
+    let mut sent = VecDeque::new();
+    while let Some(line) = lines.recv().await {
+        if sent.len() >= rate.lines {
+            if let Some(oldest) = sent.pop_front() {
+                time::sleep_until(oldest + per).await;
+            }
+        }
+        let (retries, retry_delay) = (settings.retries, settings.retry_delay);
+        if let Err(e) = send_with_retries(&output, &line, retries, retry_delay).await {
+            error!("Failed to send message chunk: {}: {}", e, line.text);
+            let mut held_back = vec![line];
+            while let Ok(line) = lines.try_recv() {
+                held_back.push(line);
+            }
+            if settings.resend_failed {
+                unsent.lock().await.extend(held_back);
+            } else {
+                warn!("Dropped {} queued lines", held_back.len());
+            }
+            send_failed.notify_one();
+            continue;
+        }
+        sent.push_back(Instant::now());
+        // Introduce a small delay to prevent rapid sending
+        time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Picks a reconnect wait from the top `jitter` fraction of the delay, with a jitter of 1
+/// being full jitter over the whole delay
+fn jittered(delay: Duration, jitter: f64, rng: &mut impl Rng) -> Duration {
+    delay.mul_f64(1.0 - jitter * rng.gen::<f64>())
+}
+
+/// Counts lost connections to tell a flapping connection from one that dropped once
+struct FlapGuard {
+    max: usize,
+    window: Duration,
+    cooldown: Duration,
+    losses: VecDeque<Instant>,
+}
+
+impl FlapGuard {
+    fn new(max: usize, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            max,
+            window,
+            cooldown,
+            losses: VecDeque::new(),
+        }
+    }
+
+    /// Records a lost connection, returning the cooldown to wait out if that makes more
+    /// than `max` in the window. The count starts over after a cooldown
+    fn record(&mut self, now: Instant) -> Option<Duration> {
+        while self.losses.front().is_some_and(|lost| now.duration_since(*lost) > self.window) {
+            self.losses.pop_front();
+        }
+        self.losses.push_back(now);
+        if self.losses.len() <= self.max {
+            return None;
+        }
+        self.losses.clear();
+        Some(self.cooldown)
+    }
+}
+
+/// Destination for the bot's replies
+#[derive(Clone)]
+pub(crate) enum Output {
+    /// Send replies over the current IRC connection, if there is one
+    Irc(Arc<RwLock<Option<Sender>>>),
+    /// Print replies to stdout, used in replay mode
+    Stdout(String),
+    /// Append replies to a file in the same form as they are printed, with our nickname
+    File(String, Arc<std::sync::Mutex<File>>),
+    /// Send replies to both, such as IRC and a file
+    Tee(Box<Output>, Box<Output>),
+}
+
+impl Output {
+    pub(crate) fn send(&self, target: &str, text: &str) -> Result<(), irc::error::Error> {
+        match self {
+            Output::Irc(sender) => Self::connected(sender)?.send_privmsg(target, text),
+            Output::Stdout(nickname) => {
+                println!("{} <{}> {}", target, nickname, text);
+                Ok(())
+            }
+            Output::File(nickname, file) => {
+                writeln!(file.lock().unwrap(), "{} <{}> {}", target, nickname, text)?;
+                Ok(())
+            }
+            Output::Tee(first, second) => first.send(target, text).and(second.send(target, text)),
+        }
+    }
+
+    fn connected(sender: &RwLock<Option<Sender>>) -> Result<Sender, irc::error::Error> {
+        sender
+            .read()
+            .ok()
+            .and_then(|sender| sender.clone())
+            .ok_or(irc::error::Error::AsyncChannelClosed)
+    }
+
+    /// Sets or, given None, clears our away status
+    pub(crate) fn set_away(&self, message: Option<&str>) -> Result<(), irc::error::Error> {
+        match self {
+            Output::Irc(sender) => {
+                Self::connected(sender)?.send(Command::AWAY(message.map(str::to_string)))
+            }
+            Output::Stdout(_) | Output::File(..) => Ok(()),
+            Output::Tee(first, second) => first.set_away(message).and(second.set_away(message)),
+        }
+    }
+
+    fn send_action(&self, target: &str, text: &str) -> Result<(), irc::error::Error> {
+        match self {
+            Output::Irc(sender) => Self::connected(sender)?.send_action(target, text),
+            Output::Stdout(nickname) => {
+                println!("{} * {} {}", target, nickname, text);
+                Ok(())
+            }
+            Output::File(nickname, file) => {
+                writeln!(file.lock().unwrap(), "{} * {} {}", target, nickname, text)?;
+                Ok(())
+            }
+            Output::Tee(first, second) => {
+                first.send_action(target, text).and(second.send_action(target, text))
+            }
+        }
+    }
+
+    /// Leaves IRC with a QUIT, nothing to do when printing to stdout or a file
+    fn quit(&self, reason: &str) -> Result<(), irc::error::Error> {
+        match self {
+            Output::Irc(sender) => Self::connected(sender)?.send_quit(reason),
+            Output::Stdout(_) | Output::File(..) => Ok(()),
+            Output::Tee(first, second) => first.quit(reason).and(second.quit(reason)),
+        }
+    }
+}
+
+/// Nick that --input messages are attributed to
+const INPUT_SENDER: &str = "user";
+
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+
+/// A client certificate and key bundled the way the IRC client's TLS takes them: a PKCS #12
+/// archive file, encrypted with a password made up for this run, in a directory of its own
+/// that only we can enter. Both are removed when this is dropped
+pub(crate) struct ClientIdentity {
+    path: PathBuf,
+    password: String,
+}
+
+impl ClientIdentity {
+    /// Bundles a PEM certificate, with any chain after it, and its PEM private key
+    pub(crate) fn bundle(cert_path: &Path, key_path: &Path) -> Result<Self, ChatroomError> {
+        let read = |path: &Path| {
+            std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))
+        };
+        let invalid = |path: &Path| {
+            let path = path.display().to_string();
+            move |e: openssl::error::ErrorStack| format!("{}: {}", path, e)
+        };
+        let mut certs = openssl::x509::X509::stack_from_pem(&read(cert_path)?)
+            .map_err(invalid(cert_path))?
+            .into_iter();
+        let cert = certs
+            .next()
+            .ok_or_else(|| format!("{}: no certificate", cert_path.display()))?;
+        let key = openssl::pkey::PKey::private_key_from_pem(&read(key_path)?)
+            .map_err(invalid(key_path))?;
+        let mut chain = openssl::stack::Stack::new().map_err(invalid(cert_path))?;
+        for ca in certs {
+            chain.push(ca).map_err(invalid(cert_path))?;
+        }
+
+        let password: String = rand::thread_rng()
+            .sample_iter(rand::distributions::Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+        let archive = openssl::pkcs12::Pkcs12::builder()
+            .name(&cert_path.display().to_string())
+            .pkey(&key)
+            .cert(&cert)
+            .ca(chain)
+            .build2(&password)
+            .and_then(|archive| archive.to_der())
+            .map_err(|e| {
+                format!("{} doesn't go with {}: {}", key_path.display(), cert_path.display(), e)
+            })?;
+
+        // Only we may read it, as it holds the key. Neither the directory nor the file may
+        // exist already, so nobody else can have put something there for us to write through
+        let suffix: String = rand::thread_rng()
+            .sample_iter(rand::distributions::Alphanumeric)
+            .take(12)
+            .map(char::from)
+            .collect();
+        let dir = env::temp_dir().join(format!("chatroom-{}-{}", std::process::id(), suffix));
+        std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+        let identity = ClientIdentity {
+            path: dir.join("client.p12"),
+            password,
+        };
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&identity.path)?;
+        file.write_all(&archive)?;
+        Ok(identity)
+    }
+}
+
+impl Drop for ClientIdentity {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Failed to remove {}: {}", self.path.display(), e);
+        }
+        if let Some(dir) = self.path.parent() {
+            if let Err(e) = std::fs::remove_dir(dir) {
+                warn!("Failed to remove {}: {}", dir.display(), e);
+            }
+        }
+    }
+}
+
+/// Connects to the IRC server and identifies, returning the client and its message stream
+pub(crate) async fn connect(
+    args: &Args,
+    identity: Option<&ClientIdentity>,
+) -> Result<(Client, irc::client::ClientStream), irc::error::Error> {
+    // IRC client configuration. Channels are left out, as `join_channels` joins them once
+    // the server has finished its welcome
+    let config = Config {
+        nickname: Some(args.nickname.clone()),
+        server: Some(args.server.clone()),
+        port: Some(args.port),
+        use_tls: Some(args.tls),
+        client_cert_path: identity.map(|identity| identity.path.display().to_string()),
+        client_cert_pass: identity.map(|identity| identity.password.clone()),
+        ping_time: Some(args.ping_interval),
+        ping_timeout: Some(args.ping_timeout),
+        // Decoded lossily, invalid bytes becoming U+FFFD (see --strict-utf8)
+        encoding: Some("UTF-8".to_string()),
+        ..Default::default()
+    };
+
+    // Create a new IRC client
+    let mut client = Client::from_config(config).await?;
+
+    // Create a stream of incoming messages
+    let stream = client.stream()?;
+
+    // Ask for the sender's account and the send time on messages, ignored if unsupported
+    client.send_cap_req(&[Capability::AccountTag, Capability::ServerTime])?;
+
+    // Identify with the server
+    client.identify()?;
+
+    Ok((client, stream))
+}
+
+/// Joins one of our channels, with its key if it has one
+fn join_channel(sender: &Sender, channel: &ChannelConfig) -> Result<(), irc::error::Error> {
+    match &channel.key {
+        Some(key) => sender.send_join_with_keys(&channel.name, key),
+        None => sender.send_join(&channel.name),
+    }
+}
+
+/// Joins our channels, which strict servers only allow after the end of the MOTD, one at a
+/// time with `delay` between them
+async fn join_channels(sender: Sender, channels: Vec<ChannelConfig>, delay: Duration) {
+    for (index, channel) in channels.iter().enumerate() {
+        if index > 0 && !delay.is_zero() {
+            time::sleep(delay).await;
+        }
+        if let Err(e) = join_channel(&sender, channel) {
+            error!("Failed to join {}: {}", channel.name, e);
+            return;
+        }
+    }
+}
+
+/// Uploads text to a paste service, returning the URL from the response body
+pub(crate) async fn upload_paste(
+    http: &reqwest::Client,
+    paste_url: &str,
+    text: &str,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let response = http
+        .post(paste_url)
+        .body(text.to_string())
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let link = response.text().await?.trim().to_string();
+    if link.is_empty() {
+        return Err("paste service returned an empty response".into());
+    }
+    Ok(link)
+}
+
+/// Sends multi-line text to a target, chunked to fit the IRC line limit
+pub(crate) async fn send_text(output: &Output, target: &str, text: &str, max_line_bytes: usize) {
+    let chunk_bytes = privmsg_payload_limit(target, max_line_bytes);
+
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        for chunk in split_into_chunks(line, chunk_bytes) {
+            if is_blank(&chunk) {
+                continue;
+            }
+            if let Err(e) = output.send(target, &chunk) {
+                error!("Failed to send message chunk: {}", e);
+            }
+            // Introduce a small delay to prevent rapid sending
+            time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+/// The channel and reason, if the message is the server turning down a JOIN
+fn join_rejection(message: &Message) -> Option<(&str, &'static str)> {
+    let Command::Response(response, args) = &message.command else {
+        return None;
+    };
+    let reason = match response {
+        Response::ERR_NOSUCHCHANNEL => "no such channel",
+        Response::ERR_TOOMANYCHANNELS => "in too many channels",
+        Response::ERR_CHANNELISFULL => "channel is full",
+        Response::ERR_INVITEONLYCHAN => "channel is invite only",
+        Response::ERR_BANNEDFROMCHAN => "banned from the channel",
+        Response::ERR_BADCHANNELKEY => "wrong channel key",
+        Response::ERR_BADCHANMASK => "bad channel name",
+        _ => return None,
+    };
+    // Arguments are: our nick, channel, text
+    Some((args.get(1)?.as_str(), reason))
+}
+
+/// Acts on the server turning down a join as --on-join-error says, returning the error to
+/// stop with when that's to exit. `attempts` counts rejections in a row per channel, keyed
+/// by `channel_key`
+fn on_join_rejected(
+    args: &Args,
+    sender: Sender,
+    channel: &ChannelConfig,
+    reason: &str,
+    attempts: &mut HashMap<String, u32>,
+) -> Option<ChatroomError> {
+    match args.on_join_error {
+        OnJoinError::Retry => {
+            let attempts = attempts.entry(channel_key(&channel.name)).or_default();
+            let delay = Duration::from_secs(args.join_retry_delay)
+                .saturating_mul(2u32.saturating_pow(*attempts))
+                .min(MAX_RECONNECT_DELAY);
+            *attempts += 1;
+            warn!("Couldn't join {} ({}), trying again in {:?}", channel.name, reason, delay);
+            let channel = channel.clone();
+            tokio::spawn(async move {
+                time::sleep(delay).await;
+                if let Err(e) = join_channel(&sender, &channel) {
+                    warn!("Failed to join {} again: {}", channel.name, e);
+                }
+            });
+            None
+        }
+        OnJoinError::Skip => {
+            warn!("Couldn't join {} ({}), skipping it", channel.name, reason);
+            None
+        }
+        OnJoinError::Exit => {
+            error!("Couldn't join {} ({}), exiting", channel.name, reason);
+            Some(ChatroomError::JoinRejected {
+                channel: channel.name.clone(),
+                reason: reason.to_string(),
+            })
+        }
+    }
+}
+
+/// Nicks present per joined channel, keyed by `channel_key`, as they're shown keyed by
+/// `fold_nick`
+pub(crate) type Participants = Arc<Mutex<HashMap<String, BTreeMap<String, String>>>>;
+
+/// Keeps the channels' nick lists current from NAMES replies, joins, parts, quits, kicks
+/// and nick changes
+async fn track_participants(participants: &Participants, message: &Message) {
+    let source = message.source_nickname();
+    let mut participants = participants.lock().await;
+
+    match &message.command {
+        // Arguments are: our nick, channel type, channel, space separated nicks
+        Command::Response(Response::RPL_NAMREPLY, args) if args.len() >= 4 => {
+            if let Some(nicks) = participants.get_mut(&channel_key(&args[2])) {
+                for nick in args[3].split_whitespace() {
+                    // Strip channel membership prefixes such as @ and +
+                    let nick = nick.trim_start_matches(['~', '&', '@', '%', '+']);
+                    nicks.insert(fold_nick(nick), nick.to_string());
+                }
+            }
+        }
+        Command::JOIN(chan, _, _) => {
+            if let (Some(nicks), Some(nick)) = (participants.get_mut(&channel_key(chan)), source) {
+                nicks.insert(fold_nick(nick), nick.to_string());
+            }
+        }
+        Command::PART(chan, _) => {
+            if let (Some(nicks), Some(nick)) = (participants.get_mut(&channel_key(chan)), source) {
+                nicks.remove(&fold_nick(nick));
+            }
+        }
+        Command::KICK(chan, nick, _) => {
+            if let Some(nicks) = participants.get_mut(&channel_key(chan)) {
+                nicks.remove(&fold_nick(nick));
+            }
+        }
+        Command::QUIT(_) => {
+            if let Some(nick) = source {
+                let folded = fold_nick(nick);
+                for nicks in participants.values_mut() {
+                    nicks.remove(&folded);
+                }
+            }
+        }
+        Command::NICK(new_nick) => {
+            if let Some(nick) = source {
+                let folded = fold_nick(nick);
+                for nicks in participants.values_mut() {
+                    if nicks.remove(&folded).is_some() {
+                        nicks.insert(fold_nick(new_nick), new_nick.clone());
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// When we last joined each channel, keyed by `channel_key`
+pub(crate) type JoinedAt = Arc<std::sync::Mutex<HashMap<String, Instant>>>;
+
+/// Peers that have left, per channel keyed by `channel_key`, with folded nicks
+pub(crate) type DepartedPeers = Arc<Mutex<HashMap<String, HashSet<String>>>>;
+
+/// Records peers leaving (PART, KICK, QUIT) and returning (JOIN)
+async fn track_peers(
+    departed: &DepartedPeers,
+    peers: &[String],
+    channels: &[ChannelConfig],
+    message: &Message,
+) {
+    let is_peer = |nick: &str| peers.iter().any(|peer| same_nick(peer, nick));
+    let source = message.source_nickname().filter(|nick| is_peer(nick));
+    let mut departed = departed.lock().await;
+
+    match (&message.command, source) {
+        (Command::JOIN(chan, _, _), Some(nick)) => {
+            if let Some(gone) = departed.get_mut(&channel_key(chan)) {
+                if gone.remove(&fold_nick(nick)) {
+                    info!("Peer {} returned to {}, resuming", nick, chan);
+                }
+            }
+        }
+        (Command::PART(chan, _), Some(nick)) => {
+            info!("Peer {} left {}, pausing replies there", nick, chan);
+            departed.entry(channel_key(chan)).or_default().insert(fold_nick(nick));
+        }
+        (Command::KICK(chan, nick, _), _) if is_peer(nick) => {
+            info!("Peer {} was kicked from {}, pausing replies there", nick, chan);
+            departed.entry(channel_key(chan)).or_default().insert(fold_nick(nick));
+        }
+        (Command::QUIT(_), Some(nick)) => {
+            info!("Peer {} quit, pausing replies", nick);
+            for channel in channels {
+                let gone = departed.entry(channel_key(&channel.name)).or_default();
+                gone.insert(fold_nick(nick));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Channels where a ban or quiet keeps our messages from getting through, keyed by
+/// `channel_key`. Replies there would only waste API calls, so turns are kept as context
+#[derive(Clone, Default)]
+pub(crate) struct Muted(Arc<std::sync::Mutex<MutedState>>);
+
+#[derive(Default)]
+struct MutedState {
+    channels: HashSet<String>,
+    /// Channels we've parted and rejoined since last being unmuted there
+    rejoined: HashSet<String>,
+    /// Our `nick!user@host` as the server last showed it in a JOIN
+    own_prefix: Option<String>,
+}
+
+impl Muted {
+    pub(crate) fn contains(&self, channel: &str) -> bool {
+        self.0.lock().unwrap().channels.contains(&channel_key(channel))
+    }
+
+    /// Whether to part and rejoin a channel we're muted in, only once until it's lifted
+    fn should_rejoin(&self, channel: &str) -> bool {
+        self.0.lock().unwrap().rejoined.insert(channel_key(channel))
+    }
+}
+
+/// Matches an IRC mask, where `*` is any run of characters and `?` any one, ignoring case
+fn mask_matches(mask: &str, text: &str) -> bool {
+    let mask: Vec<char> = mask.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    // Where to resume after the last `*`, should the rest fail to match
+    let (mut m, mut t, mut star) = (0, 0, None);
+    while t < text.len() {
+        if m < mask.len() && (mask[m] == '?' || mask[m] == text[t]) {
+            m += 1;
+            t += 1;
+        } else if m < mask.len() && mask[m] == '*' {
+            star = Some((m, t));
+            m += 1;
+        } else if let Some((star_m, star_t)) = star {
+            m = star_m + 1;
+            t = star_t + 1;
+            star = Some((star_m, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    mask[m..].iter().all(|&c| c == '*')
+}
+
+/// Records bans and quiets on us (+b or +q masks, or the server refusing a message) and
+/// their removal. Returns the channel when we've just been muted in it
+fn track_muted(muted: &Muted, nickname: &str, message: &Message) -> Option<String> {
+    let mut state = muted.0.lock().unwrap();
+    // Masks without a `!` or `@` are nicks, as when +q makes someone a founder. Extbans
+    // like `$a:account` aren't recognised, though the server refusing a message still is
+    let matches_us = |mask: &str, own_prefix: Option<&str>| {
+        if !mask.contains(['!', '@']) {
+            return false;
+        }
+        match own_prefix {
+            Some(prefix) => mask_matches(mask, prefix),
+            // Without our user@host yet, only the nick part can be checked
+            None => mask_matches(mask.split('!').next().unwrap_or_default(), nickname),
+        }
+    };
+
+    match &message.command {
+        Command::JOIN(chan, _, _)
+            if message.source_nickname().is_some_and(|n| same_nick(n, nickname)) =>
+        {
+            state.own_prefix = message.prefix.as_ref().map(|prefix| prefix.to_string());
+            // Joining again is a fresh try, and the server tells us if we're still muted
+            state.channels.remove(&channel_key(chan));
+        }
+        Command::ChannelMODE(chan, modes) => {
+            for mode in modes {
+                let (added, mask) = match mode {
+                    Mode::Plus(ChannelMode::Ban | ChannelMode::Founder, Some(mask)) => (true, mask),
+                    Mode::Minus(ChannelMode::Ban | ChannelMode::Founder, Some(mask)) => {
+                        (false, mask)
+                    }
+                    _ => continue,
+                };
+                if !matches_us(mask, state.own_prefix.as_deref()) {
+                    continue;
+                }
+                let key = channel_key(chan);
+                if added && state.channels.insert(key.clone()) {
+                    warn!("Banned or quieted in {} by {}, no longer replying there", chan, mask);
+                    return Some(chan.clone());
+                }
+                if !added && state.channels.remove(&key) {
+                    info!("Ban or quiet {} lifted in {}, replying there again", mask, chan);
+                    state.rejoined.remove(&key);
+                }
+            }
+        }
+        Command::Response(Response::ERR_CANNOTSENDTOCHAN, args)
+            if args.len() >= 2 && state.channels.insert(channel_key(&args[1])) =>
+        {
+            warn!("Can't send to {}, no longer replying there", args[1]);
+            return Some(args[1].clone());
+        }
+        _ => {}
+    }
+    None
+}
+
+/// Bytes left for the message text once `PRIVMSG <target> :` and the trailing CRLF are
+/// accounted for
+pub(crate) fn privmsg_payload_limit(target: &str, max_line_bytes: usize) -> usize {
+    let overhead = "PRIVMSG ".len() + target.len() + " :".len() + "\r\n".len();
+    max_line_bytes.saturating_sub(overhead)
+}
+
+/// A reply line that couldn't be sent, kept to send again after reconnecting
+pub(crate) struct UnsentLine {
+    pub(crate) channel: String,
+    pub(crate) text: String,
+    pub(crate) emote: bool,
+}
+
+/// Reply lines waiting for the connection to come back, with --resend-failed
+pub(crate) type Unsent = Arc<Mutex<Vec<UnsentLine>>>;
+
+/// Sends a reply line as a PRIVMSG or, for emotes, an ACTION
+fn send_line(output: &Output, line: &UnsentLine) -> Result<(), irc::error::Error> {
+    if line.emote {
+        output.send_action(&line.channel, &line.text)
+    } else {
+        output.send(&line.channel, &line.text)
+    }
+}
+
+/// Sends a reply line, trying again after a short wait in case the failure was passing
+pub(crate) async fn send_with_retries(
+    output: &Output,
+    line: &UnsentLine,
+    retries: u32,
+    delay: Duration,
+) -> Result<(), irc::error::Error> {
+    let mut attempt = 0;
+    loop {
+        match send_line(output, line) {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                warn!("Failed to send a line, retrying ({}/{}): {}", attempt, retries, e);
+                time::sleep(delay).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Sends the lines that failed to go out to a channel we just joined again, keeping any that
+/// fail again for the next time
+async fn resend_unsent(unsent: &Unsent, output: &Output, channel: &str) {
+    let mut unsent_guard = unsent.lock().await;
+    let (lines, rest): (Vec<_>, Vec<_>) = std::mem::take(&mut *unsent_guard)
+        .into_iter()
+        .partition(|line| line.channel.eq_ignore_ascii_case(channel));
+    *unsent_guard = rest;
+    drop(unsent_guard);
+
+    if lines.is_empty() {
+        return;
+    }
+    info!("Resending {} reply lines to {}", lines.len(), channel);
+
+    let unsent = Arc::clone(unsent);
+    let output = output.clone();
+    tokio::spawn(async move {
+        for line in lines {
+            if let Err(e) = send_line(&output, &line) {
+                error!("Failed to resend message chunk: {}", e);
+                unsent.lock().await.push(line);
+            }
+            // Introduce a small delay to prevent rapid sending
+            time::sleep(Duration::from_millis(100)).await;
+        }
+    });
+}
+
+/// Handles one message from the IRC stream: tracks presence, runs admin commands and
+/// buffers channel messages for the processor
+async fn handle_message(
+    args: &Args,
+    channels: &[ChannelConfig],
+    state: &SharedState,
+    message: &Message,
+) {
+    if args.trace_protocol {
+        trace!("{:?}", message.command);
+    }
+
+    track_participants(&state.participants, message).await;
+    track_peers(&state.departed_peers, &args.peers, channels, message).await;
+
+    // Back in a channel after reconnecting, so send what didn't get through before
+    if let (Command::JOIN(chan, _, _), Some(nick)) = (&message.command, message.source_nickname()) {
+        if same_nick(nick, &args.nickname) {
+            state.joined_at.lock().unwrap().insert(channel_key(chan), Instant::now());
+            resend_unsent(&state.unsent, &state.output, chan).await;
+        }
+    }
+
+    if let Command::PRIVMSG(target, msg) = &message.command {
+        let sender = message
+            .source_nickname()
+            .unwrap_or("unknown")
+            .to_string();
+
+        // Anyone may ask what we are
+        if args.whoami && msg.trim() == "!whoami" {
+            let channel = channels.iter().find(|c| c.name.eq_ignore_ascii_case(target));
+            let switched = state.switched_model.lock().await.clone();
+            let model = switched
+                .or_else(|| channel.and_then(|c| c.model.clone()))
+                .or_else(|| args.model_weights.as_ref().map(|w| w.models.join(" or ")))
+                .unwrap_or_else(|| args.model.clone());
+            let reply = whoami_reply(&model, &state.provider, state.started.elapsed());
+            let to = if channel.is_some() { target } else { &sender };
+            if let Err(e) = state.output.send(to, &reply) {
+                error!("Failed to answer !whoami: {}", e);
+            }
+            return;
+        }
+
+        // Admin commands are handled here and never reach the buffer
+        if let Some(command) = AdminCommand::parse(msg) {
+            let scope = command.scope(target, channels);
+            let account = message_tag(message, "account");
+            if !is_admin(&args.admins, &args.channel_admins, scope.as_deref(), &sender, account) {
+                warn!("Ignoring admin command from non-admin {}: {}", sender, msg);
+                return;
+            }
+
+            match command {
+                AdminCommand::History(channel) => {
+                    let history_text = match command_channel(channel, target, channels) {
+                        Some(channel) => {
+                            info!("Sending {} history to {}", channel, sender);
+                            match state.history.get(&channel) {
+                                Some(entries) => {
+                                    let entries = entries.lock().await;
+                                    if entries.is_empty() {
+                                        format!("History for {} is empty.", channel)
+                                    } else {
+                                        entries.join("\n")
+                                    }
+                                }
+                                None => format!("Not in {}.", channel),
+                            }
+                        }
+                        None => "Usage: !history <#channel>".to_string(),
+                    };
+                    let output = state.output.clone();
+                    let max_line_bytes = args.max_line_bytes;
+                    tokio::spawn(async move {
+                        send_text(&output, &sender, &history_text, max_line_bytes).await;
+                    });
+                }
+                AdminCommand::Reset(channel) => {
+                    let confirmation = match command_channel(channel, target, channels) {
+                        Some(channel) => {
+                            info!("{} reset {}", sender, channel);
+                            state.reset(&channel).await;
+                            format!("Reset {}.", channel)
+                        }
+                        None => "Usage: !reset <#channel>".to_string(),
+                    };
+                    if let Err(e) = state.output.send(&sender, &confirmation) {
+                        error!("Failed to send reset confirmation: {}", e);
+                    }
+                }
+                AdminCommand::Model(None) => {
+                    if let Err(e) = state.output.send(&sender, "Usage: !model <name>") {
+                        error!("Failed to send model usage: {}", e);
+                    }
+                }
+                AdminCommand::Model(Some(model)) => {
+                    // Checking the listing takes a request, so don't hold up the stream
+                    let state = state.clone();
+                    let channels: Vec<String> = channels.iter().map(|c| c.name.clone()).collect();
+                    tokio::spawn(async move {
+                        if let Err(reason) = state.switch_model(&model, &channels).await {
+                            warn!("{} asked for model {}: {}", sender, model, reason);
+                            if let Err(e) = state.output.send(&sender, &reason) {
+                                error!("Failed to send model rejection: {}", e);
+                            }
+                            return;
+                        }
+                        info!("{} switched the model to {}", sender, model);
+                    });
+                }
+                AdminCommand::Inject(injection) => {
+                    let joined = injection.as_ref().and_then(|injection| {
+                        let channel = command_channel(injection.channel.clone(), target, channels)?;
+                        channels.iter().find(|c| c.name.eq_ignore_ascii_case(&channel))
+                    });
+                    let confirmation = match (injection, joined) {
+                        (Some(injection), Some(channel)) => {
+                            info!(
+                                "{} injected a message from {} into {}",
+                                sender, injection.sender, channel.name
+                            );
+                            state
+                                .inject(
+                                    &channel.name,
+                                    &injection.sender,
+                                    &injection.text,
+                                    injection.quiet,
+                                )
+                                .await;
+                            format!("Injected into {}.", channel.name)
+                        }
+                        _ => "Usage: !inject [#channel] <nick> <text>".to_string(),
+                    };
+                    if let Err(e) = state.output.send(&sender, &confirmation) {
+                        error!("Failed to send inject confirmation: {}", e);
+                    }
+                }
+                AdminCommand::Snapshot => {
+                    let confirmation = match state.snapshot(None).await {
+                        Ok(path) => {
+                            info!("{} took a snapshot in {}", sender, path.display());
+                            format!("Wrote {}.", path.display())
+                        }
+                        Err(e) => {
+                            error!("Failed to write snapshot: {}", e);
+                            format!("Failed to write snapshot: {}", e)
+                        }
+                    };
+                    if let Err(e) = state.output.send(&sender, &confirmation) {
+                        error!("Failed to send snapshot confirmation: {}", e);
+                    }
+                }
+            }
+            return;
+        }
+
+        // Only process messages from the channels we joined
+        let joined = channels.iter().find(|c| c.name.eq_ignore_ascii_case(target));
+        if let Some(channel) = joined {
+            debug!("<{} {}> {}", channel.name, sender, msg);
+            state.idleness.heard(&channel.name);
+
+            let Some(msg) = conversation_text(msg, &sender) else {
+                debug!("Ignoring CTCP request from {}", sender);
+                return;
+            };
+            let sender = if args.fold_nicks { fold_nick(&sender) } else { sender };
+            let Some(msg) = filter_message(&state.filters, &sender, &msg) else {
+                debug!("A filter dropped the message from {}", sender);
+                return;
+            };
+            let msg = msg.as_str();
+
+            // The client decodes lines lossily, so invalid bytes show up as U+FFFD. A sender
+            // could also type one, which strict mode can't tell apart
+            if args.strict_utf8 && msg.contains(char::REPLACEMENT_CHARACTER) {
+                warn!("Dropping message from {} in {} with invalid UTF-8", sender, channel.name);
+                return;
+            }
+
+            if let Some(transcript) = &state.transcript {
+                // Prefer when the server says the message was sent over when we got it
+                let timestamp_ms = message_tag(message, "time")
+                    .and_then(parse_server_time)
+                    .unwrap_or_else(now_ms);
+                transcript.record(timestamp_ms, &channel.name, &sender, msg).await;
+            }
+
+            if is_listed(&args.ignored, &args.channel_ignored, Some(&channel.name), &sender) {
+                debug!("Ignoring {} in {}", sender, channel.name);
+                return;
+            }
+
+            // Add the message to the buffer with the current timestamp
+            let (msg, addressed) = addressed_message(args, msg);
+            buffer_message(&state.inbox, &channel.name, &sender, msg, addressed).await;
+        }
+    }
+}
+
+/// Every model a turn in one of the channels can be sent to: the channel's own, else each
+/// weighted one, else --model
+fn channel_models(args: &Args, channels: &[ChannelConfig]) -> BTreeSet<String> {
+    let mut models = BTreeSet::new();
+    for channel in channels {
+        match (&channel.model, &args.model_weights) {
+            (Some(model), _) => {
+                models.insert(model.clone());
+            }
+            (None, Some(weights)) => models.extend(weights.models.iter().cloned()),
+            (None, None) => {
+                models.insert(args.model.clone());
+            }
+        }
+    }
+    models
+}
+
+pub(crate) async fn run(
+    args: Args,
+    backend: Option<Box<dyn ChatBackend>>,
+    filters: Vec<Box<dyn MessageFilter>>,
+) -> Result<(), ChatroomError> {
+    // Check the configuration and stop, before opening or connecting to anything
+    if args.check_config {
+        let problems = config_problems(&args, backend.is_some(), |name| env::var(name).ok());
+        if problems.is_empty() {
+            println!("Configuration is valid");
+            return Ok(());
+        }
+        for problem in &problems {
+            error!("{}", problem);
+        }
+        let plural = if problems.len() == 1 { "" } else { "s" };
+        let summary = format!("{} configuration problem{}", problems.len(), plural);
+        return Err(ChatroomError::Config(summary));
+    }
+
+    info!("Starting IRC Logger Instance with model: {}", args.model);
+    let filters: MessageFilters = Arc::new(filters);
+    let started = Instant::now();
+    let joined_at = JoinedAt::default();
+
+    // Collect the channels to join from the command line and the channels file
+    let mut channels = args
+        .channels
+        .iter()
+        .map(|line| ChannelConfig::parse(line))
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(path) = &args.channels_from_file {
+        channels.extend(read_channels_file(path).map_err(|e| {
+            error!("Failed to read channels file {}: {}", path.display(), e);
+            e
+        })?);
+    }
+    if channels.is_empty() {
+        channels.push(ChannelConfig::parse(DEFAULT_CHANNEL)?);
+    }
+    info!(
+        "Channels: {}",
+        channels.iter().map(|c| c.name.as_str()).collect::<Vec<_>>().join(", ")
+    );
+
+    // Clone necessary variables for message processing
+    let model = args.model.clone();
+    let leader = args.leader;
+    let nickname = args.nickname.clone();
+
+    // Replies go out over whichever IRC connection is current, or to stdout when replaying,
+    // and to the output file if there is one
+    let irc_sender = Arc::new(RwLock::new(None));
+    let offline = args.replay.is_some() || args.input.is_some();
+    let output_file = match &args.output_file {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path).map_err(|e| {
+                error!("Failed to open output file {}: {}", path.display(), e);
+                e
+            })?;
+            Some(Output::File(nickname.clone(), Arc::new(std::sync::Mutex::new(file))))
+        }
+        None => None,
+    };
+    let output = match (offline, output_file) {
+        (true, output_file) => {
+            info!("Offline mode, not connecting to IRC");
+            output_file.unwrap_or_else(|| Output::Stdout(nickname.clone()))
+        }
+        (false, Some(output_file)) => {
+            Output::Tee(Box::new(Output::Irc(Arc::clone(&irc_sender))), Box::new(output_file))
+        }
+        (false, None) => Output::Irc(Arc::clone(&irc_sender)),
+    };
+
+    // Bundle the client certificate, checking it and its key before going any further
+    let identity = match (&args.tls_client_cert, &args.tls_client_key) {
+        (Some(cert), Some(key)) if !offline => {
+            let identity = ClientIdentity::bundle(cert, key).map_err(|e| {
+                error!("Failed to load the TLS client certificate: {}", e);
+                e
+            })?;
+            info!("Presenting client certificate {}", cert.display());
+            Some(identity)
+        }
+        _ => None,
+    };
+
+    // Open the transcript file, if requested
+    let transcript = match &args.transcript {
+        Some(path) => {
+            let rotation = args.transcript_max_bytes.map(|max_bytes| Rotation {
+                max_bytes,
+                keep: args.transcript_keep,
+            });
+            Some(Arc::new(Transcript::open(path, rotation).map_err(|e| {
+                error!("Failed to open transcript {}: {}", path.display(), e);
+                e
+            })?))
+        }
+        None => None,
+    };
+
+    // Set up LLM client
+    let api_key = env::var("OPENROUTER_API_KEY").ok();
+    let http = reqwest::Client::new();
+    let provider = if backend.is_some() {
+        "a custom backend".to_string()
+    } else {
+        url_host(API_BASE_URL).to_string()
+    };
+    let llm: Arc<dyn ChatBackend> = match backend {
+        Some(backend) => Arc::from(backend),
+        None => Arc::new(ProviderClient {
+            http: http.clone(),
+            api_key: api_key.clone(),
+        }),
+    };
+
+    // Just list the models, mini_openai has no call for it
+    if args.list_models {
+        for id in list_models(&http, api_key.as_deref()).await? {
+            println!("{}", id);
+        }
+        return Ok(());
+    }
+
+    // Prime the connection with a request nobody sees, and find out early if any model a
+    // channel will use fails
+    if args.warmup {
+        for model in channel_models(&args, &channels) {
+            let request = mini_openai::ChatCompletions {
+                messages: vec![mini_openai::Message {
+                    content: "ping".to_string(),
+                    role: mini_openai::ROLE_USER.to_string(),
+                }],
+                model: model.clone(),
+                max_tokens: Some(1),
+                ..Default::default()
+            };
+            let started = Instant::now();
+            match llm.chat_completions(&request).await {
+                Ok(_) => info!("Warmup request to {} succeeded in {:?}", model, started.elapsed()),
+                Err(e) => error!("Warmup request to {} failed: {}", model, e),
+            }
+        }
+    }
+
+    // Set up a single seeded PRNG shared by every random decision, so a run can be replayed
+    let seed = args.seed.unwrap_or_else(rand::random);
+    info!("Using random seed: {}", seed);
+    let rng = Arc::new(Mutex::new(ChaCha8Rng::seed_from_u64(seed)));
+
+    // Resolve the persona once, with environment variables filled in
+    let leader_prompt = args.leader_prompt.as_ref().filter(|_| args.leader);
+    let prompt = leader_prompt.or(args.system_prompt.as_ref());
+    let system_prompt = match (prompt, &args.system_prompt_file) {
+        (Some(prompt), _) => Some(prompt.clone()),
+        (None, Some(path)) => Some(std::fs::read_to_string(path).map_err(|e| {
+            error!("Failed to read system prompt file {}: {}", path.display(), e);
+            e
+        })?),
+        (None, None) => None,
+    }
+    .map(|prompt| interpolate_env(&prompt, |name| env::var(name).ok()));
+    if let Some(prompt) = &system_prompt {
+        debug!("System prompt: {}", prompt);
+    }
+
+    // Load the few-shot examples, if any
+    let examples = match &args.examples_file {
+        Some(path) => {
+            let examples = read_examples_file(path).map_err(|e| {
+                error!("Failed to read examples file {}: {}", path.display(), e);
+                e
+            })?;
+            info!("Loaded {} example messages", examples.len());
+            examples
+        }
+        None => Vec::new(),
+    };
+
+    // Load the channels' own personas, if any
+    let personas = match &args.persona_dir {
+        Some(dir) => {
+            let personas = read_personas(dir, &channels).map_err(|e| {
+                error!("Failed to read personas from {}: {}", dir.display(), e);
+                e
+            })?;
+            info!("Loaded personas for {} channels", personas.len());
+            personas
+        }
+        None => HashMap::new(),
+    };
+
+    // Set up history of chat messages per channel, each behind its own lock
+    let history = History::new(&channels);
+    if let Some(path) = &args.history_file {
+        match history.load(path) {
+            Ok(count) => info!("Loaded {} history entries from {}", count, path.display()),
+            Err(e) => {
+                match args.on_history_error {
+                    OnHistoryError::Fatal => {
+                        error!("Failed to load history from {}: {}", path.display(), e);
+                        return Err(e);
+                    }
+                    OnHistoryError::Warn => warn!(
+                        "Failed to load history from {}, starting empty: {}",
+                        path.display(),
+                        e
+                    ),
+                    OnHistoryError::Ignore => debug!(
+                        "Failed to load history from {}, starting empty: {}",
+                        path.display(),
+                        e
+                    ),
+                }
+
+                // Keep the unreadable file around instead of overwriting it on the first save
+                let aside = path.with_extension("bad");
+                if let Err(e) = std::fs::rename(path, &aside) {
+                    warn!("Failed to move {} aside: {}", path.display(), e);
+                }
+            }
+        }
+    }
+
+    // Set up the set of nicks currently in each channel
+    let participants: Participants = Arc::new(Mutex::new(
+        channels.iter().map(|c| (channel_key(&c.name), BTreeMap::new())).collect(),
+    ));
+
+    // Set up tracking of which peers have left which channels
+    let departed_peers: DepartedPeers = Arc::new(Mutex::new(HashMap::new()));
+
+    // Set up the set of nicks that have already been introduced to
+    let greeted = Arc::new(Mutex::new(HashSet::<String>::new()));
+
+    // Per-channel model overrides
+    let channel_models: HashMap<String, String> = channels
+        .iter()
+        .filter_map(|c| Some((channel_key(&c.name), c.model.clone()?)))
+        .collect();
+
+    // Set up the reset counts that queued turns are checked against
+    let generations = Generations::default();
+
+    // Set up a buffer for incoming messages
+    let batch_window = args.batch_window_ms.map(Duration::from_millis);
+    let mut pending = PendingMessages::new(batch_window, args.buffer_by, generations.clone());
+    pending.max_combined_chars = args.max_combined_chars;
+    let message_buffer: MessageBuffer = Arc::new(Mutex::new(pending));
+
+    // Set up a channel to send buffered messages for processing
+    let (buffer_tx, mut buffer_rx) = mpsc::channel::<Turn>(100);
+
+    // Clone variables to move into the background buffer handler task
+    let buffer_clone = Arc::clone(&message_buffer);
+    let once = args.once;
+    let max_concurrency = args.max_concurrency;
+    let unsent: Unsent = Arc::new(Mutex::new(Vec::new()));
+    let send_failed = Arc::new(Notify::new());
+    let presence = Arc::new(Mutex::new(Presence {
+        last_reply: Instant::now(),
+        away: false,
+    }));
+    let switched_model: SwitchedModel = Arc::new(Mutex::new(None));
+    let paused = Arc::new(AtomicBool::new(false));
+    let muted = Muted::default();
+    let stats = Arc::new(Stats::default());
+    let (history_saves, history_saver) = match &args.history_file {
+        Some(path) => {
+            let (history_saves, history_saver) = spawn_history_saver(
+                history.clone(),
+                path.clone(),
+                args.on_history_error,
+                Duration::from_secs(args.history_save_interval),
+            );
+            (Some(history_saves), Some(history_saver))
+        }
+        None => (None, None),
+    };
+    let processor = Processor {
+        llm,
+        http: http.clone(),
+        model,
+        nickname: nickname.clone(),
+        leader,
+        history: history.clone(),
+        rng: Arc::clone(&rng),
+        seeded: args.seed.is_some(),
+        stop: args.stop.clone(),
+        transcript: transcript.clone(),
+        model_weights: args.model_weights.clone(),
+        channel_models,
+        switched_model: Arc::clone(&switched_model),
+        system_prompt,
+        examples,
+        personas,
+        introduction: args.introduction.clone(),
+        response_schema: args.response_schema,
+        departed_peers: Arc::clone(&departed_peers),
+        peers: args.peers.clone(),
+        human_floor: args.human_floor.map(Duration::from_secs),
+        human_floors: std::sync::Mutex::new(HashMap::new()),
+        referee: args.referee,
+        referee_nick: args.referee_nick.clone(),
+        referee_turns: std::sync::Mutex::new(HashMap::new()),
+        dedup_window: args.dedup_window.map(Duration::from_secs),
+        recent_lines: RecentLines::default(),
+        message_rate: MessageRate::default(),
+        greeted: Arc::clone(&greeted),
+        output: output.clone(),
+        participants: Arc::clone(&participants),
+        started,
+        context: ContextSettings {
+            include_participants: args.include_participants,
+            context_stats: args.context_stats,
+            merge_same_role: args.merge_same_role,
+            turn_separator: args.turn_separator.clone(),
+            decay_after: args.decay_after,
+            decay_factor: args.decay_factor,
+        },
+        triggers: TriggerSettings {
+            questions_only: args.questions_only,
+            min_length: args.min_trigger_length,
+            suppress_if_contains: args
+                .suppress_if_contains
+                .iter()
+                .map(|t| t.to_lowercase())
+                .collect(),
+            mention_strict: args.mention_strict,
+        },
+        choices: ChoiceSettings {
+            n: args.n_choices,
+            strategy: args.choice_strategy,
+            ranking_model: args.ranking_model.clone(),
+        },
+        reply: ReplySettings {
+            format: ReplyFormat {
+                strip_newlines: args.strip_newlines,
+                strip_backticks: args.strip_backticks,
+                strip_markdown: args.strip_markdown,
+            },
+            max_line_bytes: args.max_line_bytes,
+            address_sender: args.address_sender,
+            strip_own_name: args.strip_own_name,
+            number_chunks: args.number_chunks,
+            hidden_delimiter: args.hidden_delimiter.clone(),
+            prefix: args.output_prefix.clone(),
+        },
+        quotes: QuoteSettings {
+            on_quote: args.quoted_replies,
+            similarity: args.quote_similarity,
+        },
+        thinking: ThinkingSettings {
+            message: args.thinking_message.clone(),
+            after: Duration::from_millis(args.thinking_after),
+            interval: Duration::from_secs(args.thinking_interval),
+        },
+        send: SendSettings {
+            retries: args.send_retries,
+            retry_delay: Duration::from_millis(args.send_retry_delay_ms),
+            resend_failed: args.resend_failed,
+            paste_url: args.paste_url.clone(),
+            paste_threshold: args.paste_threshold,
+        },
+        rate_limit: RateLimitSettings {
+            retries: args.rate_limit_retries,
+            delay: Duration::from_secs(args.rate_limit_delay),
+            max_delay: Duration::from_secs(args.rate_limit_max_delay),
+        },
+        refusal: RefusalSettings {
+            phrases: args.refusal_phrase.iter().map(|p| p.to_lowercase()).collect(),
+            model: args.refusal_model.clone(),
+            on_refusal: args.on_refusal,
+            reframe: args.refusal_reframe.clone(),
+            substitute: args.refusal_substitute.clone(),
+        },
+        summary: SummarySettings {
+            on_exit: args.summary_on_exit,
+            prompt: args.summary_prompt.clone(),
+            file: args.summary_file.clone(),
+            rolling: args.rolling_summary,
+            rolling_window: args.rolling_summary_window,
+            on_backlog: args.on_summary_backlog,
+        },
+        respond_channels: (!args.respond_channels.is_empty())
+            .then(|| args.respond_channels.iter().map(|c| channel_key(c)).collect()),
+        fallback_reply: args.fallback_reply.clone(),
+        last_thinking: std::sync::Mutex::new(HashMap::new()),
+        generations: generations.clone(),
+        history_saves,
+        send_failed: Arc::clone(&send_failed),
+        send_queues: SendQueues::new(args.send_rates.clone()),
+        unsent: Arc::clone(&unsent),
+        presence: Arc::clone(&presence),
+        on_reply_command: args.on_reply_command.clone(),
+        breaker: std::sync::Mutex::new(CircuitBreaker::new(
+            args.failure_threshold,
+            Duration::from_secs(args.failure_cooldown),
+        )),
+        recent_replies: RecentReplies::default(),
+        startup_grace: args.startup_grace.map(Duration::from_secs),
+        joined_at: Arc::clone(&joined_at),
+        rolling_summaries: RollingSummaries::default(),
+        paused: Arc::clone(&paused),
+        stats: Arc::clone(&stats),
+        muted: muted.clone(),
+        reply_routes: args.reply_routes.clone(),
+        turn_order: args.turn_order,
+        turn_queues: std::sync::Mutex::new(HashMap::new()),
+    };
+
+    // Kept for reviving quiet channels, which queues turns of its own. Only taken when
+    // needed, as a sender left over would keep the processor waiting at the end of the input
+    let revive_tx = args.revive_after.filter(|_| !offline).map(|_| buffer_tx.clone());
+
+    let inbox = if args.no_buffer {
+        info!("Buffering disabled, answering each message on its own");
+        Inbox::Direct(buffer_tx, generations.clone())
+    } else {
+        // Spawn a background task to handle buffered messages based on TTL or batching window
+        tokio::spawn(async move {
+            let arrivals = Arc::clone(&buffer_clone.lock().await.arrivals);
+            loop {
+                let mut buffer_guard = buffer_clone.lock().await;
+                let to_process = buffer_guard.flush_ready(Instant::now());
+
+                // Once input has ended and everything is flushed, stop so the processor can finish
+                let drained = buffer_guard.is_empty() && buffer_guard.closed;
+                let deadline = buffer_guard.next_deadline();
+
+                drop(buffer_guard); // Release the lock before sending on channel
+
+                for turn in to_process {
+                    if let Err(e) = buffer_tx.send(turn).await {
+                        error!("Failed to send buffered message to processor: {}", e);
+                    }
+                }
+
+                if drained {
+                    break;
+                }
+
+                // Sleep until the next sender or channel is due, or something new arrives
+                match deadline {
+                    Some(deadline) => tokio::select! {
+                        _ = time::sleep_until(deadline) => {}
+                        _ = arrivals.notified() => {}
+                    },
+                    None => arrivals.notified().await,
+                }
+            }
+        });
+        Inbox::Buffered(Arc::clone(&message_buffer))
+    };
+
+    // Spawn a background task to process buffered messages
+    let process_handle = tokio::spawn(async move {
+        let processor = Arc::new(processor);
+        let permits = Arc::new(Semaphore::new(max_concurrency));
+        let mut in_flight = JoinSet::new();
+
+        loop {
+            tokio::select! {
+                turn = buffer_rx.recv() => {
+                    let Some(turn) = turn else {
+                        break;
+                    };
+                    // Wait for a free slot, which also keeps turns starting in arrival order
+                    let permit = Arc::clone(&permits)
+                        .acquire_owned()
+                        .await
+                        .expect("the permits are never closed");
+                    let processor = Arc::clone(&processor);
+                    let slot = processor.queue_turn(&turn);
+                    in_flight.spawn(async move {
+                        let _permit = permit;
+                        slot.wait().await;
+                        process_turn(&processor, turn).await
+                    });
+                }
+                Some(replied) = in_flight.join_next() => {
+                    // One reply is all that was asked for
+                    if once && replied.unwrap_or(false) {
+                        info!("Replied once, exiting");
+                        if processor.summary.on_exit {
+                            post_summaries(&processor).await;
+                        }
+                        if let Err(e) = processor.output.quit("Done") {
+                            error!("Failed to quit: {}", e);
+                        }
+                        return;
+                    }
+                }
+            }
+        }
+
+        // No more turns are coming, let the ones in flight finish
+        while let Some(replied) = in_flight.join_next().await {
+            if once && replied.unwrap_or(false) {
+                info!("Replied once, exiting");
+                break;
+            }
+        }
+        if processor.summary.on_exit {
+            post_summaries(&processor).await;
+        }
+    });
+
+    if !offline {
+        let state = SharedState {
+            history,
+            participants,
+            departed_peers,
+            greeted,
+            inbox: inbox.clone(),
+            generations: generations.clone(),
+            transcript,
+            output,
+            unsent,
+            switched_model,
+            http,
+            api_key,
+            idleness: Idleness::default(),
+            paused,
+            stats,
+            model: args.model.clone(),
+            muted,
+            filters: Arc::clone(&filters),
+            provider,
+            started,
+            joined_at: Arc::clone(&joined_at),
+            config: Arc::new(snapshot_config(&args)),
+            snapshot_dir: args.snapshot_dir.clone(),
+        };
+
+        // Take commands from orchestration tooling
+        if let Some(path) = &args.control_socket {
+            let listener = bind_control_socket(path).map_err(|e| {
+                error!("Failed to listen on control socket {}: {}", path.display(), e);
+                e
+            })?;
+            info!("Listening for control commands on {}", path.display());
+            let channels = channels.iter().map(|c| c.name.clone()).collect();
+            tokio::spawn(serve_control_socket(listener, state.clone(), channels));
+        }
+        // Mark ourselves away once we've been quiet for long enough
+        if let Some(away_after) = args.away_after.map(Duration::from_secs) {
+            let presence = Arc::clone(&presence);
+            let output = state.output.clone();
+            let away_message = args.away_message.clone();
+            tokio::spawn(async move {
+                let mut interval = time::interval(Duration::from_secs(1));
+                loop {
+                    interval.tick().await;
+                    let mut presence = presence.lock().await;
+                    if presence.away || presence.last_reply.elapsed() < away_after {
+                        continue;
+                    }
+                    info!("No replies for {:?}, marking ourselves away", away_after);
+                    match output.set_away(Some(&away_message)) {
+                        Ok(()) => presence.away = true,
+                        Err(e) => debug!("Failed to set away status: {}", e),
+                    }
+                }
+            });
+        }
+
+        // Queue a turn with no messages for each channel that has gone quiet
+        if let (Some(revive_after), Some(revive_tx)) = (args.revive_after, revive_tx) {
+            let revive_after = Duration::from_secs(revive_after);
+            let idleness = state.idleness.clone();
+            let generations = state.generations.clone();
+            tokio::spawn(async move {
+                let mut interval = time::interval(Duration::from_secs(1));
+                loop {
+                    interval.tick().await;
+                    for channel in idleness.due(revive_after, Instant::now()) {
+                        info!("{} has been quiet for {:?}, reviving it", channel, revive_after);
+                        let turn = Turn {
+                            generation: generations.current(&channel),
+                            channel,
+                            messages: Vec::new(),
+                            addressed_by: HashSet::new(),
+                            revive: true,
+                            received: Instant::now(),
+                        };
+                        if let Err(e) = revive_tx.send(turn).await {
+                            error!("Failed to send revive turn to processor: {}", e);
+                        }
+                    }
+                }
+            });
+        }
+
+        let reconnect_after_silence = args.reconnect_after_silence.map(Duration::from_secs);
+        let initial_reconnect_delay = Duration::from_secs(args.reconnect_delay);
+        let mut reconnect_delay = initial_reconnect_delay;
+        let mut reconnects = 0;
+        // Rejected joins in a row per channel, keyed by `channel_key`, for the retry backoff
+        let mut join_attempts: HashMap<String, u32> = HashMap::new();
+        let mut flaps = args.flap_max.map(|max| {
+            let window = Duration::from_secs(args.flap_window);
+            FlapGuard::new(max, window, Duration::from_secs(args.flap_cooldown))
+        });
+
+        // Stay connected, reconnecting with exponential backoff whenever the connection is lost
+        loop {
+            let reason = match connect(&args, identity.as_ref()).await {
+                Ok((client, mut stream)) => {
+                    *irc_sender.write().unwrap() = Some(client.sender());
+
+                    // Nick lists are rebuilt from the NAMES replies after joining again
+                    state.participants.lock().await.values_mut().for_each(BTreeMap::clear);
+
+                    // A new connection starts out present
+                    presence.lock().await.away = false;
+
+                    // Process incoming messages and buffer them
+                    let mut last_privmsg = Instant::now();
+                    loop {
+                        let read = async {
+                            match reconnect_after_silence {
+                                Some(silence) => {
+                                    let deadline = last_privmsg + silence;
+                                    time::timeout_at(deadline, stream.next()).await.ok()
+                                }
+                                None => Some(stream.next().await),
+                            }
+                        };
+                        let next = tokio::select! {
+                            next = read => match next {
+                                Some(next) => next,
+                                None => {
+                                    let silence = reconnect_after_silence.unwrap_or_default();
+                                    break format!("no messages for {:?}", silence);
+                                }
+                            },
+                            _ = send_failed.notified() => {
+                                break "failed to send a reply".to_string();
+                            }
+                        };
+                        let message = match next {
+                            Some(Ok(message)) => message,
+                            Some(Err(e)) => break format!("connection error: {}", e),
+                            None => break "connection closed".to_string(),
+                        };
+
+                        // Registered, so the connection is good and backoff starts over
+                        if let Command::Response(Response::RPL_WELCOME, _) = &message.command {
+                            reconnect_delay = initial_reconnect_delay;
+                            reconnects = 0;
+                        }
+
+                        if let Command::PRIVMSG(..) = &message.command {
+                            last_privmsg = Instant::now();
+                        }
+
+                        // The server is done welcoming us, with or without a MOTD
+                        if let Command::Response(
+                            Response::RPL_ENDOFMOTD | Response::ERR_NOMOTD,
+                            _,
+                        ) = &message.command
+                        {
+                            info!("End of MOTD, joining channels");
+                            let delay = Duration::from_millis(args.join_delay_ms);
+                            let joining = join_channels(client.sender(), channels.clone(), delay);
+                            tokio::spawn(joining);
+                        }
+
+                        let muted_in = track_muted(&state.muted, &args.nickname, &message);
+                        let rejoin = muted_in
+                            .filter(|_| args.rejoin_when_muted)
+                            .filter(|name| state.muted.should_rejoin(name))
+                            .and_then(|name| {
+                                channels.iter().find(|c| c.name.eq_ignore_ascii_case(&name))
+                            });
+                        if let Some(channel) = rejoin {
+                            info!("Rejoining {} in case that lifts the ban or quiet", channel.name);
+                            let rejoined = client
+                                .send_part(&channel.name)
+                                .and_then(|_| join_channel(&client.sender(), channel));
+                            if let Err(e) = rejoined {
+                                break format!("failed to rejoin {}: {}", channel.name, e);
+                            }
+                        }
+
+                        // Joined, so a rejected join's backoff starts over
+                        if let Command::JOIN(chan, _, _) = &message.command {
+                            let nick = message.source_nickname().unwrap_or_default();
+                            if same_nick(nick, &args.nickname) {
+                                join_attempts.remove(&channel_key(chan));
+                            }
+                        }
+
+                        let rejected = join_rejection(&message).and_then(|(name, reason)| {
+                            let channel =
+                                channels.iter().find(|c| c.name.eq_ignore_ascii_case(name))?;
+                            let sender = client.sender();
+                            on_join_rejected(&args, sender, channel, reason, &mut join_attempts)
+                        });
+                        if let Some(e) = rejected {
+                            if let Some(path) = &args.history_file {
+                                save_history(&state.history, path, args.on_history_error).await;
+                            }
+                            return Err(e);
+                        }
+
+                        handle_message(&args, &channels, &state, &message).await;
+                    }
+                }
+                Err(e) => format!("failed to connect: {}", e),
+            };
+
+            // In --once mode the processor finishes after quitting, so this was our own QUIT
+            if process_handle.is_finished() {
+                break;
+            }
+
+            *irc_sender.write().unwrap() = None;
+            if args.max_reconnects.is_some_and(|max| reconnects >= max) {
+                error!(
+                    "Lost IRC connection ({}), giving up after {} reconnects",
+                    reason, reconnects
+                );
+                if let Some(path) = &args.history_file {
+                    save_history(&state.history, path, args.on_history_error).await;
+                }
+                return Err(ChatroomError::ReconnectsExhausted(reconnects));
+            }
+            reconnects += 1;
+
+            // Not drawn from the seeded PRNG, so reconnects don't change a replayable run
+            let jitter = args.reconnect_jitter;
+            let mut wait = jittered(reconnect_delay, jitter, &mut rand::thread_rng());
+            if let Some(cooldown) = flaps.as_mut().and_then(|flaps| flaps.record(Instant::now())) {
+                warn!(
+                    "Connection lost more than {} times in {}s, cooling down so the server \
+                     doesn't ban us",
+                    args.flap_max.unwrap_or_default(),
+                    args.flap_window
+                );
+                wait = wait.max(cooldown);
+            }
+            warn!("Lost IRC connection ({}), reconnecting in {:?}", reason, wait);
+            time::sleep(wait).await;
+            reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    } else if let Some(path) = &args.replay {
+        let entries = read_transcript(path).map_err(|e| {
+            error!("Failed to read replay transcript {}: {}", path.display(), e);
+            e
+        })?;
+        info!("Replaying {} transcript entries", entries.len());
+
+        let mut previous_ms = None;
+        for entry in entries {
+            // Our own recorded replies are regenerated, not replayed
+            if same_nick(&entry.sender, &nickname) {
+                if let Some(served_by) = &entry.served_by {
+                    debug!("Recorded reply in {} was served by {:?}", entry.channel, served_by);
+                }
+                continue;
+            }
+
+            // Stop feeding messages once the processor is done with --once
+            if process_handle.is_finished() {
+                break;
+            }
+
+            // Keep the recorded spacing so messages group the same way, but never wait
+            // longer than it takes the buffer to flush
+            if let Some(previous_ms) = previous_ms {
+                let gap = Duration::from_millis(entry.timestamp_ms.saturating_sub(previous_ms));
+                time::sleep(gap.min(Duration::from_millis(1100))).await;
+            }
+            previous_ms = Some(entry.timestamp_ms);
+
+            println!("{} <{}> {}", entry.channel, entry.sender, entry.message);
+            let Some(msg) = conversation_text(&entry.message, &entry.sender) else {
+                continue;
+            };
+            let sender = if args.fold_nicks { fold_nick(&entry.sender) } else { entry.sender };
+            let Some(msg) = filter_message(&filters, &sender, &msg) else {
+                continue;
+            };
+            let (msg, addressed) = addressed_message(&args, &msg);
+            buffer_message(&inbox, &entry.channel, &sender, msg, addressed).await;
+        }
+    } else if let Some(input) = &args.input {
+        let channel = &channels[0].name;
+        println!("{} <{}> {}", channel, INPUT_SENDER, input);
+        if let Some(input) = filter_message(&filters, INPUT_SENDER, input) {
+            let (input, addressed) = addressed_message(&args, &input);
+            buffer_message(&inbox, channel, INPUT_SENDER, input, addressed).await;
+        }
+    }
+
+    // No more input, let the buffer drain and wait for the processor to finish
+    message_buffer.lock().await.close();
+    drop(inbox);
+    process_handle.await?;
+
+    // The processor is gone, so the saver writes what's left and stops
+    if let Some(history_saver) = history_saver {
+        history_saver.await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use crate::test_support::*;
+
+    #[test]
+    fn parses_server_time_tags() {
+        assert_eq!(parse_server_time("1970-01-01T00:00:00.000Z"), Some(0));
+        assert_eq!(parse_server_time("2011-10-19T16:40:51.620Z"), Some(1_319_042_451_620));
+        assert_eq!(parse_server_time("2011-10-19T16:40:51.6Z"), Some(1_319_042_451_600));
+        assert_eq!(parse_server_time("2011-10-19T16:40:51Z"), Some(1_319_042_451_000));
+        assert_eq!(parse_server_time("2011-10-19 16:40:51"), None);
+    }
+
+    #[test]
+    fn tracks_bans_and_quiets_on_us() {
+        let muted = Muted::default();
+        let message = |line: &str| line.parse::<Message>().unwrap();
+
+        // Until we've seen our own JOIN only the nick part of a mask is checked
+        let quiet = message(":op!o@host MODE #chat +q bot!*@*\r\n");
+        assert_eq!(track_muted(&muted, "bot", &quiet).as_deref(), Some("#chat"));
+        assert!(muted.contains("#Chat"));
+        let unquiet = message(":op!o@host MODE #chat -q bot!*@*\r\n");
+        assert_eq!(track_muted(&muted, "bot", &unquiet), None);
+        assert!(!muted.contains("#chat"));
+
+        track_muted(&muted, "bot", &message(":bot!~bot@irc.example.net JOIN #chat\r\n"));
+        let ban = message(":op!o@host MODE #chat +b *!*@*.example.net\r\n");
+        assert!(track_muted(&muted, "bot", &ban).is_some());
+        let founder = message(":op!o@host MODE #other +q bot\r\n");
+        assert!(track_muted(&muted, "bot", &founder).is_none());
+
+        let refused = message(":server 404 bot #other :Cannot send to channel\r\n");
+        assert!(track_muted(&muted, "bot", &refused).is_some());
+        assert!(muted.should_rejoin("#other"));
+        assert!(!muted.should_rejoin("#other"));
+    }
+
+    #[tokio::test]
+    async fn tracks_participants_whatever_the_case() {
+        let participants: Participants = Arc::default();
+        participants.lock().await.insert("#rust".to_string(), BTreeMap::new());
+        let message = |line: &str| line.parse::<Message>().unwrap();
+        let nicks = || async {
+            let participants = participants.lock().await;
+            participants["#rust"].values().cloned().collect::<Vec<_>>()
+        };
+
+        let names = ":irc.example.net 353 bot = #Rust :@Alice +Bob[m] carol\r\n";
+        track_participants(&participants, &message(names)).await;
+        assert_eq!(nicks().await, ["Alice", "Bob[m]", "carol"]);
+
+        track_participants(&participants, &message(":ALICE!a@host PART #rust\r\n")).await;
+        let kick = ":op!o@host KICK #rust bob{m} :bye\r\n";
+        track_participants(&participants, &message(kick)).await;
+        track_participants(&participants, &message(":CAROL!c@host NICK Carol_\r\n")).await;
+        assert_eq!(nicks().await, ["Carol_"]);
+    }
+
+    #[test]
+    fn matches_masks() {
+        assert!(mask_matches("*!*@*.example.net", "bot!~bot@irc.Example.net"));
+        assert!(mask_matches("b?t!*", "bot!~bot@host"));
+        assert!(!mask_matches("*!*@other.net", "bot!~bot@example.net"));
+        assert!(!mask_matches("bot", "bots"));
+    }
+
+    #[test]
+    fn jitter_stays_within_the_delay() {
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        let delay = Duration::from_secs(10);
+        assert_eq!(jittered(delay, 0.0, &mut rng), delay);
+        for _ in 0..100 {
+            let wait = jittered(delay, 0.5, &mut rng);
+            assert!(wait > Duration::from_secs(5) && wait <= delay);
+        }
+        assert!(parse_fraction("1.5").is_err());
+    }
+
+    #[test]
+    fn payload_limit_accounts_for_privmsg_overhead() {
+        // "PRIVMSG #rust :" is 15 bytes, plus 2 for CRLF
+        assert_eq!(privmsg_payload_limit("#rust", 512), 495);
+        assert_eq!(privmsg_payload_limit("#rust", 10), 0);
+    }
+
+    #[test]
+    fn bundles_a_client_certificate_for_tls() {
+        use openssl::{asn1::Asn1Time, hash::MessageDigest, pkey::PKey, rsa::Rsa, x509};
+        use std::os::unix::fs::PermissionsExt;
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = x509::X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "bot").unwrap();
+        let name = name.build();
+        let mut cert = x509::X509::builder().unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+        let dir = env::temp_dir().join(format!("chatroom-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert_path, cert.build().to_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+        let identity = ClientIdentity::bundle(&cert_path, &key_path).unwrap();
+        let archive = openssl::pkcs12::Pkcs12::from_der(&std::fs::read(&identity.path).unwrap());
+        let parsed = archive.unwrap().parse2(&identity.password).unwrap();
+        assert!(parsed.pkey.unwrap().public_eq(&key));
+        let path = identity.path.clone();
+        let private = std::fs::metadata(path.parent().unwrap()).unwrap();
+        assert_eq!(private.permissions().mode() & 0o777, 0o700);
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        drop(identity);
+        assert!(!path.exists());
+        assert!(!path.parent().unwrap().exists());
+
+        let missing = ClientIdentity::bundle(&dir.join("missing.pem"), &key_path);
+        assert!(matches!(missing, Err(ChatroomError::Config(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn flapping_connections_cool_down() {
+        let mut flaps = FlapGuard::new(2, Duration::from_secs(60), Duration::from_secs(900));
+        let start = Instant::now();
+        assert_eq!(flaps.record(start), None);
+        assert_eq!(flaps.record(start + Duration::from_secs(10)), None);
+        assert_eq!(flaps.record(start + Duration::from_secs(20)), Some(Duration::from_secs(900)));
+        // Counting starts over, and losses out of the window no longer count
+        assert_eq!(flaps.record(start + Duration::from_secs(30)), None);
+        assert_eq!(flaps.record(start + Duration::from_secs(100)), None);
+        assert_eq!(flaps.record(start + Duration::from_secs(200)), None);
+    }
+
+    #[test]
+    fn reads_why_a_join_was_rejected() {
+        let full: Message = ":irc.example.net 471 bot #rust :Cannot join channel (+l)\r\n"
+            .parse()
+            .unwrap();
+        assert_eq!(join_rejection(&full), Some(("#rust", "channel is full")));
+        let invite: Message = ":irc.example.net 473 bot #ops :Cannot join channel (+i)\r\n"
+            .parse()
+            .unwrap();
+        assert_eq!(join_rejection(&invite), Some(("#ops", "channel is invite only")));
+        let other: Message = ":irc.example.net 401 bot alice :No such nick\r\n".parse().unwrap();
+        assert_eq!(join_rejection(&other), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn queues_lines_to_rate_limited_targets() {
+        assert!(SendRate::parse("2/10").is_ok_and(|rate| rate.target.is_none()));
+        assert!(SendRate::parse("#Busy=0/10").is_err());
+        assert!(SendRate::parse("#busy=2").is_err());
+
+        let backend = Arc::new(MockBackend {
+            reply: "one\ntwo\nthree".to_string(),
+            ..Default::default()
+        });
+        let mut processor = test_processor(backend);
+        processor.send_queues = SendQueues::new(vec![SendRate::parse("#Rust=2/1").unwrap()]);
+        assert!(processor.send_queues.rate("#go").is_none());
+        let file = TempFile::new("rated.txt");
+        processor.output = file.output();
+
+        assert!(process_turn(&processor, turn(&[("alice", "count")])).await);
+        advance_in_steps(Duration::from_millis(500)).await;
+        assert_eq!(file.read(), "#rust <bot> one\n#rust <bot> two\n");
+        advance_in_steps(Duration::from_millis(1000)).await;
+        let late = file.read();
+        assert_eq!(late, "#rust <bot> one\n#rust <bot> two\n#rust <bot> three\n");
+
+        // Lines that don't fit in a full queue are dropped
+        let flood = (0..SEND_QUEUE_CAPACITY + 5)
+            .map(|i| UnsentLine {
+                channel: "#flood".to_string(),
+                text: i.to_string(),
+                emote: false,
+            })
+            .collect();
+        let rate = SendRate::parse("#flood=1000/1").unwrap();
+        processor.send_queues.enqueue(&processor, "#flood", &rate, flood);
+        advance_in_steps(Duration::from_secs(60)).await;
+        let flooded = file.read();
+        let flooded: Vec<_> = flooded[late.len()..].lines().collect();
+        assert_eq!(flooded.len(), SEND_QUEUE_CAPACITY);
+        assert_eq!(flooded.last().unwrap(), &format!("#flood <bot> {}", SEND_QUEUE_CAPACITY - 1));
+    }
+
+    #[test]
+    fn warms_up_the_models_channels_use() {
+        let args = Args::try_parse_from([
+            "chatroom",
+            "--model",
+            "fallback",
+            "--model-weights",
+            "a=1,b=2",
+        ])
+        .unwrap();
+        let channels = [
+            ChannelConfig::parse("#rust,own").unwrap(),
+            ChannelConfig::parse("#go").unwrap(),
+        ];
+        let expected = BTreeSet::from(["a", "b", "own"].map(String::from));
+        assert_eq!(channel_models(&args, &channels), expected);
+
+        let args = Args::try_parse_from(["chatroom", "--model", "fallback"]).unwrap();
+        let expected = BTreeSet::from(["fallback".to_string()]);
+        assert_eq!(channel_models(&args, &channels[1..]), expected);
+    }
+
+    #[tokio::test]
+    async fn counts_configuration_problems() {
+        let check = |channels: &[&str]| {
+            let mut argv = vec!["chatroom", "--model", "mock", "--check-config"];
+            for channel in channels {
+                argv.extend(["-c", channel]);
+            }
+            let backend: Box<dyn ChatBackend> = Box::new(Arc::new(MockBackend::default()));
+            run(Args::try_parse_from(argv).unwrap(), Some(backend), Vec::new())
+        };
+        let problems = |result: Result<(), ChatroomError>| result.unwrap_err().to_string();
+        assert_eq!(problems(check(&["rust"]).await), "1 configuration problem");
+        assert_eq!(problems(check(&["rust", "go"]).await), "2 configuration problems");
+        assert!(check(&["#rust"]).await.is_ok());
+    }
+}
@@ -0,0 +1,417 @@
+//! Channel history and transcripts, in memory and on disk
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::sync::{Mutex, mpsc};
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, error, info, warn};
+use crate::{config::*, provider::*, turns::*, ChatroomError, now_ms};
+
+/// Maps a `nick: message` history entry to a chat role by its nick prefix. Entries without
+/// a usable prefix can't be attributed, so they are logged and treated as user messages
+/// rather than risk being mislabeled as our own.
+pub(crate) fn history_role(entry: &str, nickname: &str) -> &'static str {
+    match entry.split_once(": ") {
+        Some((nick, _)) if same_nick(nick, nickname) => mini_openai::ROLE_ASSISTANT,
+        Some((nick, _)) if !nick.is_empty() && !nick.contains(char::is_whitespace) => {
+            mini_openai::ROLE_USER
+        }
+        _ => {
+            warn!("Can't tell who said history entry, defaulting to user role: {}", entry);
+            mini_openai::ROLE_USER
+        }
+    }
+}
+
+/// A single line of a JSONL transcript
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct TranscriptEntry {
+    /// Milliseconds since the Unix epoch
+    pub(crate) timestamp_ms: u64,
+    pub(crate) channel: String,
+    pub(crate) sender: String,
+    pub(crate) message: String,
+    /// Who served our replies, as far as the provider said
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) served_by: Option<ServedBy>,
+    /// A reply of ours that was a refusal and never sent
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    refusal: bool,
+}
+
+/// When to rotate a transcript and how many rotated files to keep
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Rotation {
+    pub(crate) max_bytes: u64,
+    pub(crate) keep: usize,
+}
+
+/// The transcript file being appended to and how big it is
+struct TranscriptFile {
+    file: File,
+    size: u64,
+}
+
+impl TranscriptFile {
+    fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { file, size })
+    }
+}
+
+/// Append-only JSONL record of channel traffic, readable by `--replay`. With a rotation, a
+/// file about to outgrow it is renamed with a millisecond timestamp suffix and a fresh one
+/// started
+pub(crate) struct Transcript {
+    path: PathBuf,
+    rotation: Option<Rotation>,
+    file: Mutex<TranscriptFile>,
+}
+
+impl Transcript {
+    pub(crate) fn open(path: &Path, rotation: Option<Rotation>) -> std::io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            rotation,
+            file: Mutex::new(TranscriptFile::open(path)?),
+        })
+    }
+
+    pub(crate) async fn record(
+        &self,
+        timestamp_ms: u64,
+        channel: &str,
+        sender: &str,
+        message: &str,
+    ) {
+        self.write(TranscriptEntry {
+            timestamp_ms,
+            channel: channel.to_string(),
+            sender: sender.to_string(),
+            message: message.to_string(),
+            served_by: None,
+            refusal: false,
+        })
+        .await;
+    }
+
+    /// Records one of our replies along with who served it
+    pub(crate) async fn record_reply(
+        &self,
+        channel: &str,
+        sender: &str,
+        reply: &str,
+        served_by: &ServedBy,
+    ) {
+        self.write(TranscriptEntry {
+            timestamp_ms: now_ms(),
+            channel: channel.to_string(),
+            sender: sender.to_string(),
+            message: reply.to_string(),
+            served_by: Some(served_by.clone()),
+            refusal: false,
+        })
+        .await;
+    }
+
+    /// Records a refusal by the model, which is never sent
+    pub(crate) async fn record_refusal(
+        &self,
+        channel: &str,
+        sender: &str,
+        reply: &str,
+        by: &ServedBy,
+    ) {
+        self.write(TranscriptEntry {
+            timestamp_ms: now_ms(),
+            channel: channel.to_string(),
+            sender: sender.to_string(),
+            message: reply.to_string(),
+            served_by: Some(by.clone()),
+            refusal: true,
+        })
+        .await;
+    }
+
+    async fn write(&self, entry: TranscriptEntry) {
+        let line = match serde_json::to_string(&entry) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize transcript entry: {}", e);
+                return;
+            }
+        };
+
+        let mut file = self.file.lock().await;
+        if let Some(rotation) = self.rotation {
+            // A single entry bigger than the limit still gets a file to itself
+            if file.size > 0 && file.size + line.len() as u64 + 1 > rotation.max_bytes {
+                if let Err(e) = self.rotate(&mut file, rotation.keep) {
+                    error!("Failed to rotate transcript {}: {}", self.path.display(), e);
+                }
+            }
+        }
+        match writeln!(file.file, "{}", line) {
+            Ok(()) => file.size += line.len() as u64 + 1,
+            Err(e) => error!("Failed to write transcript entry: {}", e),
+        }
+    }
+
+    /// Moves the current file aside, starts a fresh one and removes the rotated files
+    /// beyond the ones to keep
+    fn rotate(&self, file: &mut TranscriptFile, keep: usize) -> std::io::Result<()> {
+        // Stamps only go up, so the newest rotated file sorts last even within a millisecond
+        let stamp = match rotated_transcripts(&self.path)?.last() {
+            Some((last, _)) => now_ms().max(last + 1),
+            None => now_ms(),
+        };
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        let rotated = self.path.with_file_name(format!("{}.{}", name, stamp));
+        std::fs::rename(&self.path, &rotated)?;
+        *file = TranscriptFile::open(&self.path)?;
+        info!("Rotated transcript to {}", rotated.display());
+
+        for (_, old) in rotated_transcripts(&self.path)?.iter().rev().skip(keep) {
+            debug!("Removing old transcript {}", old.display());
+            std::fs::remove_file(old)?;
+        }
+        Ok(())
+    }
+}
+
+/// Files rotated out of the transcript at `path` with their stamps, oldest first
+fn rotated_transcripts(path: &Path) -> std::io::Result<Vec<(u64, PathBuf)>> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut rotated: Vec<(u64, PathBuf)> = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let stamp = file_name
+            .to_str()
+            .and_then(|file_name| file_name.strip_prefix(name.as_ref()))
+            .and_then(|rest| rest.strip_prefix('.'))
+            .and_then(|stamp| stamp.parse().ok());
+        if let Some(stamp) = stamp {
+            rotated.push((stamp, entry.path()));
+        }
+    }
+    rotated.sort();
+    Ok(rotated)
+}
+
+/// Reads every entry of a JSONL transcript, skipping blank lines
+pub(crate) fn read_transcript(path: &Path) -> Result<Vec<TranscriptEntry>, ChatroomError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(serde_json::from_str(&line)?);
+    }
+
+    Ok(entries)
+}
+
+/// One channel's chat history, as `nick: message` entries
+type ChannelHistory = Arc<Mutex<Vec<String>>>;
+
+/// Chat history per channel, keyed by `channel_key`.
+///
+/// Every channel's history has its own lock, so a busy channel never holds up another. The
+/// map itself is only locked for as long as it takes to find or add a channel, and never
+/// across an await.
+#[derive(Clone)]
+pub(crate) struct History(Arc<std::sync::Mutex<HashMap<String, ChannelHistory>>>);
+
+impl History {
+    pub(crate) fn new(channels: &[ChannelConfig]) -> Self {
+        let histories = channels.iter().map(|c| (channel_key(&c.name), ChannelHistory::default()));
+        Self(Arc::new(std::sync::Mutex::new(histories.collect())))
+    }
+
+    /// A channel's history, if it is one we keep history for
+    pub(crate) fn get(&self, channel: &str) -> Option<ChannelHistory> {
+        self.0.lock().unwrap().get(&channel_key(channel)).cloned()
+    }
+
+    /// A channel's history, started empty if it's the first message seen there
+    pub(crate) fn channel(&self, channel: &str) -> ChannelHistory {
+        let mut histories = self.0.lock().unwrap();
+        Arc::clone(histories.entry(channel_key(channel)).or_default())
+    }
+
+    /// Fills the history from a file written by `save`, returning how many entries it held.
+    /// A missing file is a fresh start, and nothing is loaded unless the whole file parses
+    pub(crate) fn load(&self, path: &Path) -> Result<usize, ChatroomError> {
+        if !path.exists() {
+            return Ok(0);
+        }
+        let saved: HashMap<String, Vec<String>> =
+            serde_json::from_reader(BufReader::new(File::open(path)?))?;
+
+        let count = saved.values().map(Vec::len).sum();
+        let mut histories = self.0.lock().unwrap();
+        for (channel, entries) in saved {
+            histories.insert(channel_key(&channel), Arc::new(Mutex::new(entries)));
+        }
+        Ok(count)
+    }
+
+    /// A copy of every channel's history, keyed by `channel_key`
+    pub(crate) async fn snapshot(&self) -> BTreeMap<String, Vec<String>> {
+        let histories: BTreeMap<_, _> = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(channel, entries)| (channel.clone(), Arc::clone(entries)))
+            .collect();
+
+        // Every channel stays locked until all are copied, so the copy is of one moment.
+        // Locking in key order keeps two copies from deadlocking
+        let mut locked = Vec::new();
+        for (channel, entries) in &histories {
+            locked.push((channel, entries.lock().await));
+        }
+        locked.into_iter().map(|(channel, entries)| (channel.clone(), entries.clone())).collect()
+    }
+
+    /// Writes every channel's history to a file, by way of a temporary file so that a failed
+    /// write never leaves a truncated history behind
+    async fn save(&self, path: &Path) -> Result<(), ChatroomError> {
+        let saved = self.snapshot().await;
+        let temporary = path.with_extension("tmp");
+        tokio::fs::write(&temporary, serde_json::to_vec_pretty(&saved)?).await?;
+        tokio::fs::rename(&temporary, path).await?;
+        Ok(())
+    }
+}
+
+/// Saves the history, reporting failures as the policy says
+pub(crate) async fn save_history(history: &History, path: &Path, policy: OnHistoryError) {
+    if let Err(e) = history.save(path).await {
+        match policy {
+            OnHistoryError::Ignore => debug!("Failed to save history to {}: {}", path.display(), e),
+            OnHistoryError::Warn => warn!("Failed to save history to {}: {}", path.display(), e),
+            OnHistoryError::Fatal => error!("Failed to save history to {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Changes to save before the debounce interval is up
+const HISTORY_SAVE_BATCH: usize = 20;
+
+/// Saves the history in a task of its own, so turns never wait on the disk. Each message on
+/// the returned sender marks the history changed; changes are collected until the interval
+/// is up or `HISTORY_SAVE_BATCH` have come in, then saved at once. Closing the sender saves
+/// whatever is left and ends the task
+pub(crate) fn spawn_history_saver(
+    history: History,
+    path: PathBuf,
+    policy: OnHistoryError,
+    interval: Duration,
+) -> (mpsc::Sender<()>, tokio::task::JoinHandle<()>) {
+    let (tx, mut rx) = mpsc::channel(HISTORY_SAVE_BATCH);
+    let handle = tokio::spawn(async move {
+        while rx.recv().await.is_some() {
+            let deadline = Instant::now() + interval;
+            let mut changes = 1;
+            while changes < HISTORY_SAVE_BATCH {
+                match time::timeout_at(deadline, rx.recv()).await {
+                    Ok(Some(())) => changes += 1,
+                    Ok(None) | Err(_) => break,
+                }
+            }
+            debug!("Saving {} history changes", changes);
+            save_history(&history, &path, policy).await;
+        }
+    });
+    (tx, handle)
+}
+
+/// Marks the history changed for the saver, if there is one. A full queue already has a
+/// save coming, so the change is dropped rather than waited on
+pub(crate) fn history_changed(history_saves: Option<&mpsc::Sender<()>>) {
+    if let Some(history_saves) = history_saves {
+        let _ = history_saves.try_send(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use crate::test_support::*;
+
+    #[test]
+    fn history_roles_follow_each_entry_speaker() {
+        assert_eq!(history_role("bot: hello", "bot"), mini_openai::ROLE_ASSISTANT);
+        assert_eq!(history_role("Bot: hello", "bot"), mini_openai::ROLE_ASSISTANT);
+        assert_eq!(history_role("alice: hi bot: x", "bot"), mini_openai::ROLE_USER);
+        assert_eq!(history_role("no prefix here", "bot"), mini_openai::ROLE_USER);
+    }
+
+    #[tokio::test]
+    async fn history_survives_a_save_and_load() {
+        let file = TempFile::new("history.json");
+        let path = &file.path;
+        let channels = vec![ChannelConfig::parse("#rust").unwrap()];
+
+        let history = History::new(&channels);
+        history.channel("#Rust").lock().await.push("alice: hi".to_string());
+        history.save(path).await.unwrap();
+
+        let loaded = History::new(&channels);
+        assert_eq!(loaded.load(path).unwrap(), 1);
+        assert_eq!(*loaded.channel("#rust").lock().await, vec!["alice: hi".to_string()]);
+
+        // A corrupt file loads nothing
+        std::fs::write(path, "{not json").unwrap();
+        let fresh = History::new(&channels);
+        assert!(fresh.load(path).is_err());
+        assert!(fresh.channel("#rust").lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn transcript_rotates_past_its_size() {
+        let dir = env::temp_dir().join(format!("rotation-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("transcript.jsonl");
+        let rotation = Rotation {
+            max_bytes: 200,
+            keep: 2,
+        };
+        let transcript = Transcript::open(&path, Some(rotation)).unwrap();
+        for i in 0..20 {
+            transcript.record(i, "#rust", "alice", "a line long enough to fill up files").await;
+        }
+
+        let rotated: Vec<PathBuf> =
+            rotated_transcripts(&path).unwrap().into_iter().map(|(_, path)| path).collect();
+        let sizes: Vec<u64> = std::iter::once(&path)
+            .chain(&rotated)
+            .map(|path| std::fs::metadata(path).unwrap().len())
+            .collect();
+        let newest = read_transcript(rotated.last().unwrap()).unwrap();
+        let current = read_transcript(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(rotated.len(), 2);
+        assert!(sizes.iter().all(|&size| size <= 200));
+        assert_eq!(current.last().unwrap().timestamp_ms, 19);
+        assert_eq!(newest.last().unwrap().timestamp_ms + 1, current[0].timestamp_ms);
+    }
+}
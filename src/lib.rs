@@ -0,0 +1,290 @@
+use clap::Parser;
+use std::{
+    env,
+    error::Error,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::Level;
+use tracing_subscriber::{filter::Targets, layer::SubscriberExt, FmtSubscriber};
+
+mod config;
+mod connection;
+mod control;
+mod history;
+mod processor;
+mod provider;
+mod reply;
+mod turns;
+#[cfg(test)]
+mod test_support;
+
+pub use config::Args;
+pub use provider::{ChatBackend, ServedBy, ServedCompletion, Usage};
+pub use turns::MessageFilter;
+
+use connection::run;
+
+/// Why a run, or setting part of one up, failed
+#[derive(Debug)]
+pub enum ChatroomError {
+    /// The IRC connection couldn't be made or used
+    Connection(irc::error::Error),
+    /// The provider turned down our API key
+    Auth(String),
+    /// A flag, or a file named by one, is unusable
+    Config(String),
+    /// A request to the provider failed
+    Llm(Box<dyn Error + Send + Sync>),
+    Io(std::io::Error),
+    /// The IRC connection kept dropping until --max-reconnects ran out
+    ReconnectsExhausted(u32),
+    /// The server wouldn't let us into a channel, with --on-join-error exit
+    JoinRejected { channel: String, reason: String },
+}
+
+impl std::fmt::Display for ChatroomError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChatroomError::Connection(e) => write!(f, "IRC connection failed: {}", e),
+            ChatroomError::Auth(reason) => write!(f, "not authorized: {}", reason),
+            ChatroomError::Config(reason) => write!(f, "{}", reason),
+            ChatroomError::Llm(e) => write!(f, "API request failed: {}", e),
+            ChatroomError::Io(e) => write!(f, "{}", e),
+            ChatroomError::ReconnectsExhausted(reconnects) => {
+                write!(f, "gave up after {} reconnects", reconnects)
+            }
+            ChatroomError::JoinRejected { channel, reason } => {
+                write!(f, "couldn't join {}: {}", channel, reason)
+            }
+        }
+    }
+}
+
+impl Error for ChatroomError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ChatroomError::Connection(e) => Some(e),
+            ChatroomError::Llm(e) => Some(e.as_ref()),
+            ChatroomError::Io(e) => Some(e),
+            ChatroomError::Auth(_)
+            | ChatroomError::Config(_)
+            | ChatroomError::ReconnectsExhausted(_)
+            | ChatroomError::JoinRejected { .. } => None,
+        }
+    }
+}
+
+impl From<irc::error::Error> for ChatroomError {
+    fn from(e: irc::error::Error) -> Self {
+        ChatroomError::Connection(e)
+    }
+}
+
+impl From<mini_openai::Error> for ChatroomError {
+    fn from(e: mini_openai::Error) -> Self {
+        ChatroomError::Llm(Box::new(e))
+    }
+}
+
+impl From<reqwest::Error> for ChatroomError {
+    fn from(e: reqwest::Error) -> Self {
+        match e.status() {
+            Some(status @ (reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN)) => {
+                ChatroomError::Auth(status.to_string())
+            }
+            _ => ChatroomError::Llm(Box::new(e)),
+        }
+    }
+}
+
+impl From<std::io::Error> for ChatroomError {
+    fn from(e: std::io::Error) -> Self {
+        ChatroomError::Io(e)
+    }
+}
+
+impl From<tokio::task::JoinError> for ChatroomError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        ChatroomError::Io(e.into())
+    }
+}
+
+/// Malformed files named by flags
+impl From<serde_json::Error> for ChatroomError {
+    fn from(e: serde_json::Error) -> Self {
+        ChatroomError::Config(e.to_string())
+    }
+}
+
+impl From<String> for ChatroomError {
+    fn from(reason: String) -> Self {
+        ChatroomError::Config(reason)
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Sets up logging the way the command line wants it. Embedders with their own subscriber
+/// can leave this out
+pub fn init_tracing(args: &Args) {
+    // Trace level is only for our own protocol logging, dependencies stay at debug
+    let own_level = if args.trace_protocol { Level::TRACE } else { Level::DEBUG };
+    let filter = Targets::new()
+        .with_default(Level::DEBUG)
+        .with_target(env!("CARGO_CRATE_NAME"), own_level);
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(Level::TRACE)
+        .with_target(false) // Hide the target (module path)
+        .with_thread_names(false)
+        .with_thread_ids(false)
+        .finish()
+        .with(filter);
+
+    tracing::subscriber::set_global_default(subscriber)
+        .expect("Unable to set global tracing subscriber");
+}
+
+/// A bot configured from the command line or a `BotBuilder`, ready to run
+pub struct Bot {
+    args: Args,
+    backend: Option<Box<dyn ChatBackend>>,
+    filters: Vec<Box<dyn MessageFilter>>,
+}
+
+/// Builds a `Bot` from the same options the command line takes, so both share defaults and
+/// checks. Options without a method of their own can be given with `arg` or `flag`
+pub struct BotBuilder {
+    argv: Vec<String>,
+    backend: Option<Box<dyn ChatBackend>>,
+    filters: Vec<Box<dyn MessageFilter>>,
+}
+
+impl Bot {
+    pub fn builder() -> BotBuilder {
+        BotBuilder {
+            argv: vec!["chatroom".to_string()],
+            backend: None,
+            filters: Vec::new(),
+        }
+    }
+
+    pub fn from_args(args: Args) -> Self {
+        Bot {
+            args,
+            backend: None,
+            filters: Vec::new(),
+        }
+    }
+
+    /// Converses until the input runs out, or for as long as the IRC connection lasts
+    pub async fn run(self) -> Result<(), ChatroomError> {
+        run(self.args, self.backend, self.filters).await
+    }
+}
+
+impl BotBuilder {
+    pub fn server(self, server: &str) -> Self {
+        self.arg("--server", server)
+    }
+
+    pub fn port(self, port: u16) -> Self {
+        self.arg("--port", &port.to_string())
+    }
+
+    pub fn nickname(self, nickname: &str) -> Self {
+        self.arg("--nickname", nickname)
+    }
+
+    /// Adds a channel, as `#channel[,model[,key]]`
+    pub fn channel(self, channel: &str) -> Self {
+        self.arg("--channel", channel)
+    }
+
+    pub fn model(self, model: &str) -> Self {
+        self.arg("--model", model)
+    }
+
+    pub fn system_prompt(self, prompt: &str) -> Self {
+        self.arg("--system-prompt", prompt)
+    }
+
+    pub fn leader_prompt(self, prompt: &str) -> Self {
+        self.arg("--leader-prompt", prompt)
+    }
+
+    /// Sends chat completions here instead of to the provider
+    pub fn backend(mut self, backend: impl ChatBackend + 'static) -> Self {
+        self.backend = Some(Box::new(backend));
+        self
+    }
+
+    /// Adds a filter for incoming channel messages, run after the ones added before it
+    pub fn filter(mut self, filter: impl MessageFilter + 'static) -> Self {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    /// Any other option, by its long name, e.g. `arg("--input", "hello")`
+    pub fn arg(mut self, name: &str, value: &str) -> Self {
+        self.argv.push(name.to_string());
+        self.argv.push(value.to_string());
+        self
+    }
+
+    /// Any other switch, by its long name, e.g. `flag("--leader")`
+    pub fn flag(mut self, name: &str) -> Self {
+        self.argv.push(name.to_string());
+        self
+    }
+
+    pub fn build(self) -> Result<Bot, ChatroomError> {
+        let args = Args::try_parse_from(&self.argv).map_err(|e| e.to_string())?;
+        Ok(Bot {
+            args,
+            backend: self.backend,
+            filters: self.filters,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::test_support::*;
+
+    #[tokio::test]
+    async fn builder_runs_a_bot_on_its_own_backend() {
+        let backend = Arc::new(MockBackend {
+            reply: "hello yourself".to_string(),
+            ..Default::default()
+        });
+        let file = TempFile::new("builder.txt");
+        let bot = Bot::builder()
+            .model("mock")
+            .nickname("mockbot")
+            .channel("#rust")
+            .system_prompt("Be brief")
+            .flag("--leader")
+            .arg("--input", "hello?")
+            .arg("--output-file", file.path.to_str().unwrap())
+            .backend(Arc::clone(&backend))
+            .filter(|_: &str, msg: &str| Some(msg.replace("hello", "[redacted]")))
+            .build()
+            .unwrap();
+
+        bot.run().await.unwrap();
+        assert_eq!(file.read(), "#rust <mockbot> hello yourself\n");
+        let requests = backend.requests.lock().unwrap();
+        assert_eq!(requests[0][0], (mini_openai::ROLE_SYSTEM.to_string(), "Be brief".to_string()));
+        assert_eq!(requests[0].last().unwrap().1, "user: [redacted]?");
+
+        let bad_port = Bot::builder().model("mock").arg("--port", "none").build();
+        assert!(matches!(bad_port, Err(ChatroomError::Config(_))));
+    }
+}
@@ -0,0 +1,1908 @@
+//! Turning batched messages into replies from the model
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::OpenOptions,
+    io::Write,
+    path::PathBuf,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, Notify, OwnedMutexGuard, mpsc};
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, error, info, warn};
+use crate::{
+    config::*, connection::*, control::*, history::*, now_ms, provider::*, reply::*, turns::*,
+};
+
+/// Added after the history when a quiet channel is being revived
+const REVIVE_PROMPT: &str = "The conversation has gone quiet. Pick it back up with something that follows on from what was said so far.";
+
+/// Asked of the --refusal-model about each reply
+const REFUSAL_CLASSIFIER_PROMPT: &str = "Does the following chat message refuse or decline to take part, rather than joining the conversation? Answer only yes or no.";
+
+/// Details of a sent reply, passed to the --on-reply-command
+#[derive(Serialize, Debug)]
+struct ReplyEvent {
+    /// Milliseconds since the Unix epoch
+    timestamp_ms: u64,
+    channel: String,
+    /// The nick being answered, empty when reviving a quiet channel
+    sender: String,
+    model: String,
+    emote: bool,
+    reply: String,
+}
+
+/// Runs the --on-reply-command through the shell with the event on stdin, logging failures
+async fn run_reply_command(command: &str, event: &ReplyEvent) {
+    let input = match serde_json::to_vec(event) {
+        Ok(input) => input,
+        Err(e) => {
+            error!("Failed to serialize reply event: {}", e);
+            return;
+        }
+    };
+
+    let child = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            error!("Failed to run reply command: {}", e);
+            return;
+        }
+    };
+
+    if let Some(mut stdin) = child.stdin.take() {
+        if let Err(e) = stdin.write_all(&input).await {
+            warn!("Failed to write reply to the reply command: {}", e);
+        }
+        // Dropping stdin closes it so the command sees the end of its input
+    }
+
+    match child.wait().await {
+        Ok(status) if !status.success() => warn!("Reply command exited with {}", status),
+        Ok(_) => {}
+        Err(e) => error!("Failed to wait for reply command: {}", e),
+    }
+}
+
+/// Everything the processor needs to answer turns, shared by the turns in flight
+pub(crate) struct Processor {
+    pub(crate) llm: Arc<dyn ChatBackend>,
+    pub(crate) http: reqwest::Client,
+    pub(crate) model: String,
+    pub(crate) nickname: String,
+    pub(crate) leader: bool,
+    pub(crate) history: History,
+    pub(crate) rng: Arc<Mutex<ChaCha8Rng>>,
+    pub(crate) seeded: bool,
+    pub(crate) stop: Vec<String>,
+    pub(crate) transcript: Option<Arc<Transcript>>,
+    pub(crate) model_weights: Option<ModelWeights>,
+    pub(crate) channel_models: HashMap<String, String>,
+    pub(crate) switched_model: SwitchedModel,
+    pub(crate) system_prompt: Option<String>,
+    pub(crate) examples: Vec<mini_openai::Message>,
+    /// Keyed by `channel_key`
+    pub(crate) personas: HashMap<String, Persona>,
+    pub(crate) introduction: Option<String>,
+    pub(crate) response_schema: bool,
+    pub(crate) departed_peers: DepartedPeers,
+    pub(crate) peers: Vec<String>,
+    pub(crate) human_floor: Option<Duration>,
+    /// Until when a human has the floor, per channel keyed by `channel_key`
+    pub(crate) human_floors: std::sync::Mutex<HashMap<String, Instant>>,
+    pub(crate) referee: bool,
+    pub(crate) referee_nick: Option<String>,
+    /// The peer last told to speak, per channel keyed by `channel_key`
+    pub(crate) referee_turns: std::sync::Mutex<HashMap<String, String>>,
+    pub(crate) dedup_window: Option<Duration>,
+    pub(crate) recent_lines: RecentLines,
+    pub(crate) message_rate: MessageRate,
+    pub(crate) greeted: Arc<Mutex<HashSet<String>>>,
+    pub(crate) output: Output,
+    pub(crate) participants: Participants,
+    /// When we started, for --context-stats
+    pub(crate) started: Instant,
+    pub(crate) context: ContextSettings,
+    pub(crate) triggers: TriggerSettings,
+    pub(crate) choices: ChoiceSettings,
+    pub(crate) reply: ReplySettings,
+    pub(crate) quotes: QuoteSettings,
+    pub(crate) thinking: ThinkingSettings,
+    pub(crate) send: SendSettings,
+    pub(crate) rate_limit: RateLimitSettings,
+    pub(crate) refusal: RefusalSettings,
+    pub(crate) summary: SummarySettings,
+    /// Channels we may reply in, keyed by `channel_key`, or None to reply everywhere
+    pub(crate) respond_channels: Option<HashSet<String>>,
+    pub(crate) fallback_reply: String,
+    /// When the thinking message was last posted, per channel keyed by `channel_key`
+    pub(crate) last_thinking: std::sync::Mutex<HashMap<String, Instant>>,
+    pub(crate) generations: Generations,
+    /// Told about every history change, when the history is saved to a file
+    pub(crate) history_saves: Option<mpsc::Sender<()>>,
+    /// Woken when a reply line couldn't be sent even after retrying, to reconnect. The wakeup
+    /// is kept until the connection next waits for it
+    pub(crate) send_failed: Arc<Notify>,
+    pub(crate) send_queues: SendQueues,
+    pub(crate) unsent: Unsent,
+    pub(crate) presence: Arc<Mutex<Presence>>,
+    pub(crate) on_reply_command: Option<String>,
+    pub(crate) breaker: std::sync::Mutex<CircuitBreaker>,
+    pub(crate) recent_replies: RecentReplies,
+    pub(crate) startup_grace: Option<Duration>,
+    pub(crate) joined_at: JoinedAt,
+    /// Keyed by `channel_key`
+    pub(crate) rolling_summaries: RollingSummaries,
+    /// Set over the control socket to keep turns as context without replying
+    pub(crate) paused: Arc<AtomicBool>,
+    pub(crate) stats: Arc<Stats>,
+    pub(crate) reply_routes: Vec<ReplyRoute>,
+    pub(crate) muted: Muted,
+    pub(crate) turn_order: TurnOrder,
+    /// The lock held by the latest turn queued for each --turn-order key until it's done
+    pub(crate) turn_queues: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+/// What goes into the prompt besides the channel's history
+pub(crate) struct ContextSettings {
+    pub(crate) include_participants: bool,
+    pub(crate) context_stats: bool,
+    pub(crate) merge_same_role: bool,
+    pub(crate) turn_separator: String,
+    pub(crate) decay_after: Option<usize>,
+    pub(crate) decay_factor: f64,
+}
+
+/// Which turns are worth answering at all
+pub(crate) struct TriggerSettings {
+    pub(crate) questions_only: bool,
+    pub(crate) min_length: Option<usize>,
+    /// Lowercased
+    pub(crate) suppress_if_contains: Vec<String>,
+    pub(crate) mention_strict: bool,
+}
+
+/// How many candidate replies to ask for and how to pick one
+pub(crate) struct ChoiceSettings {
+    pub(crate) n: usize,
+    pub(crate) strategy: ChoiceStrategy,
+    pub(crate) ranking_model: Option<String>,
+}
+
+/// How a reply is cleaned up and cut into lines
+pub(crate) struct ReplySettings {
+    pub(crate) format: ReplyFormat,
+    pub(crate) max_line_bytes: usize,
+    pub(crate) address_sender: bool,
+    pub(crate) strip_own_name: bool,
+    pub(crate) number_chunks: bool,
+    pub(crate) hidden_delimiter: Option<String>,
+    /// Put before every line, from --output-prefix
+    pub(crate) prefix: Option<String>,
+}
+
+/// What to do about people quoting our replies back at us
+pub(crate) struct QuoteSettings {
+    pub(crate) on_quote: Option<QuotedReplies>,
+    pub(crate) similarity: f64,
+}
+
+/// The placeholder posted while a slow reply is on its way
+pub(crate) struct ThinkingSettings {
+    pub(crate) message: Option<String>,
+    pub(crate) after: Duration,
+    pub(crate) interval: Duration,
+}
+
+/// How reply lines reach the channel
+#[derive(Clone)]
+pub(crate) struct SendSettings {
+    pub(crate) retries: u32,
+    pub(crate) retry_delay: Duration,
+    pub(crate) resend_failed: bool,
+    pub(crate) paste_url: Option<String>,
+    pub(crate) paste_threshold: usize,
+}
+
+/// Retrying requests the provider turned away for going too fast
+pub(crate) struct RateLimitSettings {
+    pub(crate) retries: u32,
+    pub(crate) delay: Duration,
+    pub(crate) max_delay: Duration,
+}
+
+/// Spotting and handling replies that refuse to take part
+pub(crate) struct RefusalSettings {
+    /// Lowercased
+    pub(crate) phrases: Vec<String>,
+    pub(crate) model: Option<String>,
+    pub(crate) on_refusal: OnRefusal,
+    pub(crate) reframe: String,
+    pub(crate) substitute: String,
+}
+
+/// Summaries on exit and the rolling ones kept in the prompt
+pub(crate) struct SummarySettings {
+    pub(crate) on_exit: bool,
+    pub(crate) prompt: String,
+    pub(crate) file: Option<PathBuf>,
+    /// Summarize after this many turns
+    pub(crate) rolling: Option<usize>,
+    pub(crate) rolling_window: usize,
+    pub(crate) on_backlog: SummaryBacklog,
+}
+
+/// A turn's place in its --turn-order queue, kept for as long as the turn is being answered
+pub(crate) struct TurnSlot {
+    /// Held by the turn before until it's done
+    before: Option<Arc<Mutex<()>>>,
+    /// Holds back the turn after until this one is done
+    _ours: Option<OwnedMutexGuard<()>>,
+}
+
+impl TurnSlot {
+    pub(crate) async fn wait(&self) {
+        if let Some(before) = &self.before {
+            let _ = before.lock().await;
+        }
+    }
+}
+
+impl Processor {
+    /// Queues a turn behind the last one with the same --turn-order key. This happens as
+    /// turns are flushed, so they are answered in that order even though their tasks may
+    /// start in any order
+    pub(crate) fn queue_turn(&self, turn: &Turn) -> TurnSlot {
+        let key = match (self.turn_order, turn.messages.last()) {
+            (TurnOrder::None, _) => {
+                return TurnSlot {
+                    before: None,
+                    _ours: None,
+                }
+            }
+            (TurnOrder::Sender, Some((sender, _))) => {
+                format!("{} {}", channel_key(&turn.channel), fold_nick(sender))
+            }
+            (TurnOrder::Channel | TurnOrder::Sender, _) => channel_key(&turn.channel),
+        };
+        let ours = Arc::new(Mutex::new(()));
+        let guard = Arc::clone(&ours).try_lock_owned().expect("nobody else has the new lock");
+        let before = self.turn_queues.lock().unwrap().insert(key, ours);
+        TurnSlot {
+            before,
+            _ours: Some(guard),
+        }
+    }
+}
+
+/// Picks the reply to go with among the candidates the model returned, following
+/// --choice-strategy. `conversation` is what the candidates answer, shown to a ranking model
+async fn choose_reply(
+    processor: &Processor,
+    conversation: &[mini_openai::Message],
+    mut candidates: Vec<String>,
+) -> Option<String> {
+    if candidates.len() < 2 {
+        return candidates.pop();
+    }
+
+    let index = match processor.choices.strategy {
+        ChoiceStrategy::First => 0,
+        ChoiceStrategy::Random => processor.rng.lock().await.gen_range(0..candidates.len()),
+        ChoiceStrategy::All => return Some(number_candidates(&candidates)),
+        ChoiceStrategy::Rank => {
+            let mut messages: Vec<_> = conversation
+                .iter()
+                .map(|message| mini_openai::Message {
+                    content: message.content.clone(),
+                    role: message.role.clone(),
+                })
+                .collect();
+            messages.push(mini_openai::Message {
+                content: format!(
+                    "Candidate replies to the conversation above:\n{}\n\
+                     Answer with only the number of the best reply.",
+                    number_candidates(&candidates)
+                ),
+                role: mini_openai::ROLE_SYSTEM.to_string(),
+            });
+            let request = mini_openai::ChatCompletions {
+                messages,
+                model: processor.choices.ranking_model.clone().unwrap_or_default(),
+                ..Default::default()
+            };
+            match processor.llm.chat_completions(&request).await {
+                Ok(response) => {
+                    let answer = response
+                        .choices
+                        .first()
+                        .map(|choice| choice.message.content.as_str())
+                        .unwrap_or_default();
+                    parse_ranking(answer, candidates.len()).unwrap_or_else(|| {
+                        warn!("Ranking model answered {:?}, using the first reply", answer);
+                        0
+                    })
+                }
+                Err(e) => {
+                    error!("Ranking request failed, using the first reply: {}", e);
+                    0
+                }
+            }
+        }
+    };
+    debug!("Picked reply {} of {}", index + 1, candidates.len());
+    Some(candidates.swap_remove(index))
+}
+
+/// Whether a reply is the model balking, going by --refusal-phrase and then by asking the
+/// --refusal-model. A failed classifier request counts as no refusal
+async fn is_refusal(processor: &Processor, reply: &str) -> bool {
+    let lowered = reply.to_lowercase();
+    if processor.refusal.phrases.iter().any(|phrase| lowered.contains(phrase.as_str())) {
+        return true;
+    }
+    let Some(model) = &processor.refusal.model else {
+        return false;
+    };
+    let request = mini_openai::ChatCompletions {
+        messages: vec![
+            mini_openai::Message {
+                content: REFUSAL_CLASSIFIER_PROMPT.to_string(),
+                role: mini_openai::ROLE_SYSTEM.to_string(),
+            },
+            mini_openai::Message {
+                content: reply.to_string(),
+                role: mini_openai::ROLE_USER.to_string(),
+            },
+        ],
+        model: model.clone(),
+        ..Default::default()
+    };
+    match processor.llm.chat_completions(&request).await {
+        Ok(response) => response.choices.first().is_some_and(|choice| {
+            choice.message.content.trim().to_lowercase().starts_with("yes")
+        }),
+        Err(e) => {
+            warn!("Refusal check failed, taking the reply as it is: {}", e);
+            false
+        }
+    }
+}
+
+/// The messages sent to the model for a turn: system notes, examples, then the channel's
+/// history with each entry's role taken from who said it
+async fn build_messages(
+    processor: &Processor,
+    channel_id: &str,
+    mut channel_history: &[String],
+    revive: bool,
+) -> Vec<mini_openai::Message> {
+    let mut messages = vec![];
+    let persona = processor.personas.get(channel_id);
+
+    let system_prompt = persona.and_then(|p| p.system_prompt.as_ref());
+    if let Some(system_prompt) = system_prompt.or(processor.system_prompt.as_ref()) {
+        messages.push(mini_openai::Message {
+            content: system_prompt.clone(),
+            role: mini_openai::ROLE_SYSTEM.to_string(),
+        });
+    }
+
+    messages.push(mini_openai::Message{
+    content: "When being called, you have an option to not answer. If you dont want to answer or engage, you shall simply write ignore, without any additional formatting".to_string(),
+    role: mini_openai::ROLE_SYSTEM.to_string(),
+});
+
+    if processor.response_schema {
+        messages.push(mini_openai::Message {
+            content: STRUCTURED_REPLY_PROMPT.to_string(),
+            role: mini_openai::ROLE_SYSTEM.to_string(),
+        });
+    }
+
+    // Let the model know who is in the room
+    if processor.context.include_participants {
+        let participants_guard = processor.participants.lock().await;
+        if let Some(nicks) = participants_guard.get(channel_id).filter(|n| !n.is_empty()) {
+            messages.push(mini_openai::Message {
+                content: format!(
+                    "Participants: {}",
+                    nicks.values().cloned().collect::<Vec<_>>().join(", ")
+                ),
+                role: mini_openai::ROLE_SYSTEM.to_string(),
+            });
+        }
+    }
+
+    // Let the model know how lively the room is
+    if processor.context.context_stats {
+        let now = Instant::now();
+        let participants = processor.participants.lock().await.get(channel_id).map(BTreeMap::len);
+        let rate = processor.message_rate.per_minute(channel_id, processor.started, now);
+        let mut stats = vec![
+            format!("{:.1} messages a minute lately", rate),
+            format!("running for {} minutes", now.duration_since(processor.started).as_secs() / 60),
+        ];
+        if let Some(participants) = participants.filter(|&count| count > 0) {
+            stats.insert(0, format!("{} participants", participants));
+        }
+        messages.push(mini_openai::Message {
+            content: format!("Channel stats: {}", stats.join(", ")),
+            role: mini_openai::ROLE_SYSTEM.to_string(),
+        });
+    }
+
+    // Pinned few-shot examples go ahead of the live history
+    let examples = persona.and_then(|p| p.examples.as_ref());
+    for example in examples.unwrap_or(&processor.examples) {
+        messages.push(mini_openai::Message {
+            content: example.content.clone(),
+            role: example.role.clone(),
+        });
+    }
+
+    // What the rolling summary sums up goes in its place, unless a reset made it stale
+    let summary = processor.rolling_summaries.lock().unwrap().get(channel_id).cloned();
+    let summary = summary.filter(|summary| {
+        summary.generation == processor.generations.current(channel_id)
+            && summary.covers <= channel_history.len()
+    });
+    if let Some(summary) = summary {
+        if let Some(text) = &summary.text {
+            messages.push(mini_openai::Message {
+                content: format!("Summary of the conversation so far: {}", text),
+                role: mini_openai::ROLE_SYSTEM.to_string(),
+            });
+            channel_history = &channel_history[summary.covers..];
+        }
+        // Behind on summing up, so cut what it doesn't cover rather than let it pile up
+        if summary.truncating {
+            let start = channel_history.len().saturating_sub(processor.summary.rolling_window);
+            channel_history = &channel_history[start..];
+        }
+    }
+
+    // Keep the latest entries whole and thin out the distant past, saying so
+    let decayed = match processor.context.decay_after {
+        Some(keep) => decay_history(channel_history, keep, processor.context.decay_factor),
+        None => channel_history.iter().collect(),
+    };
+    let left_out = channel_history.len() - decayed.len();
+    if left_out > 0 {
+        messages.push(mini_openai::Message {
+            content: format!(
+                "The older part of the conversation is abridged, {} of its messages left out.",
+                left_out
+            ),
+            role: mini_openai::ROLE_SYSTEM.to_string(),
+        });
+    }
+
+    // Build the messages with the correct roles, taken from who said each entry
+    for message in decayed {
+        messages.push(mini_openai::Message {
+            content: message.clone(),
+            role: history_role(message, &processor.nickname).to_string(),
+        });
+    }
+
+    if revive {
+        messages.push(mini_openai::Message {
+            content: REVIVE_PROMPT.to_string(),
+            role: mini_openai::ROLE_SYSTEM.to_string(),
+        });
+    }
+
+    if processor.context.merge_same_role {
+        messages = merge_same_role(messages, &processor.context.turn_separator);
+    }
+    messages
+}
+
+/// The history entries to send when older ones decay: the last `keep` as they are, then in
+/// each `keep` entries further back, one in every `factor` to the power of how far back
+fn decay_history(history: &[String], keep: usize, factor: f64) -> Vec<&String> {
+    let recent = history.len().saturating_sub(keep);
+    let mut kept: Vec<&String> = history[..recent]
+        .iter()
+        .rev()
+        .enumerate()
+        .filter(|(distance, _)| {
+            let band = distance / keep.max(1) + 1;
+            let stride = factor.max(1.0).powi(band.min(64) as i32).round() as usize;
+            distance % stride.max(1) == 0
+        })
+        .map(|(_, entry)| entry)
+        .collect();
+    kept.reverse();
+    kept.extend(&history[recent..]);
+    kept
+}
+
+/// Folds each run of consecutive messages with the same role into one message, their
+/// contents joined by the separator
+pub(crate) fn merge_same_role(
+    messages: Vec<mini_openai::Message>,
+    separator: &str,
+) -> Vec<mini_openai::Message> {
+    let mut merged: Vec<mini_openai::Message> = Vec::new();
+    for message in messages {
+        match merged.last_mut() {
+            Some(last) if last.role == message.role => {
+                last.content.push_str(separator);
+                last.content.push_str(&message.content);
+            }
+            _ => merged.push(message),
+        }
+    }
+    merged
+}
+
+/// The peer to speak after the one last told to, in the order of `peers` and going round.
+/// Whoever just spoke and peers that are away are passed over, unless nobody else is left
+fn next_speaker<'a>(
+    peers: &'a [String],
+    last_directed: Option<&str>,
+    last_sender: &str,
+    away: &HashSet<String>,
+) -> Option<&'a str> {
+    let start = last_directed
+        .and_then(|last| peers.iter().position(|peer| same_nick(peer, last)))
+        .map_or(0, |last| last + 1);
+    let mut candidates = peers
+        .iter()
+        .cycle()
+        .skip(start)
+        .take(peers.len())
+        .filter(|peer| !away.contains(&fold_nick(peer)));
+    let first = candidates.clone().next();
+    candidates.find(|peer| !same_nick(peer, last_sender)).or(first).map(String::as_str)
+}
+
+/// As the referee, tells the next peer it's their turn instead of replying
+async fn direct_next_speaker(processor: &Processor, turn: &Turn) -> bool {
+    let channel_id = channel_key(&turn.channel);
+    if processor.paused.load(Ordering::SeqCst) || processor.muted.contains(&turn.channel) {
+        return false;
+    }
+
+    let last_sender = turn.messages.last().map_or("", |(sender, _)| sender.as_str());
+    let away = processor.departed_peers.lock().await.get(&channel_id).cloned().unwrap_or_default();
+    let next = {
+        let mut referee_turns = processor.referee_turns.lock().unwrap();
+        let last_directed = referee_turns.get(&channel_id).map(String::as_str);
+        let next = next_speaker(&processor.peers, last_directed, last_sender, &away);
+        let Some(next) = next else {
+            debug!("No peer left in {} to give the turn to", turn.channel);
+            return false;
+        };
+        referee_turns.insert(channel_id, next.to_string());
+        next.to_string()
+    };
+
+    let directive = format!("@{} your turn", next);
+    info!("Giving the turn in {} to {}", turn.channel, next);
+    send_text(&processor.output, &turn.channel, &directive, processor.reply.max_line_bytes).await;
+    if let Some(transcript) = &processor.transcript {
+        transcript.record(now_ms(), &turn.channel, &processor.nickname, &directive).await;
+    }
+    true
+}
+
+/// Asks the model to sum up a conversation with the --summary-prompt, None if it had nothing
+/// to say
+async fn summarize(
+    llm: &dyn ChatBackend,
+    model: &str,
+    prompt: &str,
+    conversation: String,
+) -> Result<Option<String>, mini_openai::Error> {
+    let request = mini_openai::ChatCompletions {
+        messages: vec![
+            mini_openai::Message {
+                content: prompt.to_string(),
+                role: mini_openai::ROLE_SYSTEM.to_string(),
+            },
+            mini_openai::Message {
+                content: conversation,
+                role: mini_openai::ROLE_USER.to_string(),
+            },
+        ],
+        model: model.to_string(),
+        ..Default::default()
+    };
+    let response = llm.chat_completions(&request).await?;
+    let summary = response.choices.into_iter().next().map(|c| c.message.content);
+    Ok(summary.filter(|summary| !summary.trim().is_empty()))
+}
+
+/// A channel's --rolling-summary and what it covers
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RollingSummary {
+    text: Option<String>,
+    /// How many of the oldest history entries the text sums up, sent in their place
+    covers: usize,
+    /// The channel's generation when summed up, as a reset makes it stale
+    generation: u64,
+    /// Turns since it was last brought up to date
+    turns: usize,
+    /// Being brought up to date in the background
+    in_flight: bool,
+    /// Fell behind with --on-summary-backlog truncate, so the entries it doesn't cover are
+    /// cut to the window until it's done
+    truncating: bool,
+}
+
+/// Rolling summaries keyed by `channel_key`, shared with the tasks bringing them up to date
+pub(crate) type RollingSummaries = Arc<std::sync::Mutex<HashMap<String, RollingSummary>>>;
+
+/// Counts a turn towards the channel's next --rolling-summary
+fn count_summary_turn(processor: &Processor, channel: &str) {
+    let mut summaries = processor.rolling_summaries.lock().unwrap();
+    summaries.entry(channel_key(channel)).or_default().turns += 1;
+}
+
+/// Once `every` turns have been counted, brings the channel's rolling summary up to date in
+/// the background: the summary so far and the entries since, up to the last
+/// --rolling-summary-window. While that's still going, --on-summary-backlog decides
+async fn start_rolling_summary(processor: &Processor, channel: &str, every: usize) {
+    let channel_id = channel_key(channel);
+    let generation = processor.generations.current(channel);
+    let (previous, previous_covers) = {
+        let mut summaries = processor.rolling_summaries.lock().unwrap();
+        let summary = summaries.entry(channel_id.clone()).or_default();
+        if summary.turns < every {
+            return;
+        }
+        summary.turns = 0;
+        // Never more than one request per channel, however fast the history grows
+        if summary.in_flight {
+            match processor.summary.on_backlog {
+                SummaryBacklog::Skip => {
+                    warn!("Still summing up {}, skipping this rolling summary", channel)
+                }
+                SummaryBacklog::Truncate => {
+                    warn!(
+                        "Still summing up {}, sending only the last {} entries until done",
+                        channel, processor.summary.rolling_window
+                    );
+                    summary.truncating = true;
+                }
+            }
+            return;
+        }
+        // A reset leaves nothing of the old summary to build on
+        if summary.generation != generation {
+            *summary = RollingSummary {
+                generation,
+                ..Default::default()
+            };
+        }
+        summary.in_flight = true;
+        (summary.text.clone(), summary.covers)
+    };
+
+    let entries = processor.history.channel(channel).lock().await.clone();
+    let covers = entries.len().saturating_sub(processor.summary.rolling_window);
+    if covers <= previous_covers {
+        let mut summaries = processor.rolling_summaries.lock().unwrap();
+        if let Some(summary) = summaries.get_mut(&channel_id) {
+            summary.in_flight = false;
+        }
+        return;
+    }
+    let mut conversation = previous
+        .map(|text| format!("Summary of the conversation before this: {}\n\n", text))
+        .unwrap_or_default();
+    conversation.push_str(&entries[previous_covers..covers].join("\n"));
+
+    let llm = Arc::clone(&processor.llm);
+    let model = processor.model.clone();
+    let prompt = processor.summary.prompt.clone();
+    let summaries = Arc::clone(&processor.rolling_summaries);
+    let channel = channel.to_string();
+    tokio::spawn(async move {
+        let text = summarize(&*llm, &model, &prompt, conversation).await;
+        let mut summaries = summaries.lock().unwrap();
+        let summary = summaries.entry(channel_id).or_default();
+        summary.in_flight = false;
+        summary.truncating = false;
+        match text {
+            Ok(Some(text)) => {
+                debug!("Rolling summary of {} covers {} entries: {}", channel, covers, text);
+                summary.text = Some(text);
+                summary.covers = covers;
+                summary.generation = generation;
+            }
+            // Keep the old summary and try again next time it's due
+            Ok(None) => warn!("The model had no rolling summary of {}", channel),
+            Err(e) => error!("Failed to update the rolling summary of {}: {}", channel, e),
+        }
+    });
+}
+
+/// Asks the model to sum up each channel's discussion, posting the summary there and
+/// saving it to the --summary-file
+pub(crate) async fn post_summaries(processor: &Processor) {
+    for (channel, entries) in processor.history.snapshot().await {
+        if entries.is_empty() {
+            continue;
+        }
+        let conversation = entries.join("\n");
+        let summary = match summarize(
+            &*processor.llm,
+            &processor.model,
+            &processor.summary.prompt,
+            conversation,
+        )
+        .await
+        {
+            Ok(summary) => summary,
+            Err(e) => {
+                error!("Failed to summarize {}: {}", channel, e);
+                continue;
+            }
+        };
+        let Some(summary) = summary else {
+            warn!("The model had no summary of {}", channel);
+            continue;
+        };
+
+        info!("Summary of {}: {}", channel, summary);
+        send_text(&processor.output, &channel, &summary, processor.reply.max_line_bytes).await;
+        if let Some(transcript) = &processor.transcript {
+            transcript.record(now_ms(), &channel, &processor.nickname, &summary).await;
+        }
+        if let Some(path) = &processor.summary.file {
+            let saved = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}\n{}\n", channel, summary.trim()));
+            if let Err(e) = saved {
+                error!("Failed to save the summary to {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Answers one turn, returning whether a reply was sent
+pub(crate) async fn process_turn(processor: &Processor, mut turn: Turn) -> bool {
+    let Processor {
+        llm,
+        http,
+        model,
+        nickname,
+        history,
+        rng,
+        stop,
+        transcript,
+        model_weights,
+        channel_models,
+        switched_model,
+        introduction,
+        departed_peers,
+        peers,
+        human_floors,
+        recent_lines,
+        greeted,
+        output,
+        respond_channels,
+        fallback_reply,
+        last_thinking,
+        generations,
+        history_saves,
+        unsent,
+        presence,
+        on_reply_command,
+        breaker,
+        triggers,
+        reply: reply_settings,
+        thinking,
+        send,
+        rate_limit,
+        ..
+    } = processor;
+    let leader = processor.leader;
+    let seeded = processor.seeded;
+    let response_schema = processor.response_schema;
+
+    let channel = turn.channel.clone();
+    let channel_id = channel_key(&channel);
+    // Covers the wait for the sender or channel to go quiet and for a free slot
+    let buffered = turn.received.elapsed();
+
+    // Queued before the channel was reset, so it belongs to the forgotten context
+    if turn.generation != generations.current(&channel) {
+        debug!("Dropping a turn queued before {} was reset", channel);
+        return false;
+    }
+    processor.stats.turns.fetch_add(1, Ordering::Relaxed);
+
+    if processor.referee {
+        return direct_next_speaker(processor, &turn).await;
+    }
+
+    // With a referee, only its directives naming us call for a reply. They're about who
+    // speaks, not part of the conversation, so they don't go in the history
+    let mut directed = false;
+    if let Some(referee) = &processor.referee_nick {
+        turn.messages.retain(|(from, msg)| {
+            let is_directive = same_nick(from, referee);
+            directed |= is_directive && mentions(msg, nickname, true);
+            !is_directive
+        });
+    }
+    // People quoting us back would have us argue with ourselves
+    if let Some(quoted_replies) = processor.quotes.on_quote {
+        let similarity = processor.quotes.similarity;
+        let recent_replies = &processor.recent_replies;
+        turn.messages.retain_mut(|(from, msg)| {
+            if !recent_replies.quoted_in(&channel, msg, similarity) {
+                return true;
+            }
+            debug!("{} quoted one of our replies in {}", from, channel);
+            match quoted_replies {
+                QuotedReplies::Tag => {
+                    *msg = format!("(quoting {}) {}", nickname, msg);
+                    true
+                }
+                QuotedReplies::Skip => false,
+            }
+        });
+    }
+
+    // A revive or a directive asks for a reply whatever the turn says
+    let prompted = turn.revive || directed;
+
+    // The last speaker of the turn is the one being answered, and nobody when reviving
+    let sender = match turn.messages.last() {
+        Some((sender, _)) => sender.clone(),
+        None if prompted => String::new(),
+        None => return false,
+    };
+    let address_sender = reply_settings.address_sender && !sender.is_empty();
+
+    if processor.summary.rolling.is_some() {
+        count_summary_turn(processor, &channel);
+    }
+
+    // Lock the channel's history for reading
+    let channel_lock = history.channel(&channel);
+    let mut channel_history = channel_lock.lock().await;
+
+    // Add the turn's messages to the channel's history, in the order they were said
+    processor.message_rate.record(&channel, turn.messages.len(), turn.received);
+    for (sender, msg) in &turn.messages {
+        debug!("<Buffered {} {}> {}", channel, sender, msg);
+        processor.stats.heard(sender, msg);
+        channel_history.push(format!("{}: {}", sender, msg));
+    }
+
+    let messages = build_messages(processor, &channel_id, &channel_history, turn.revive).await;
+
+    // Derive a per-turn sampling seed from the shared PRNG when running seeded
+    let request_seed = if seeded {
+        Some(rng.lock().await.gen())
+    } else {
+        None
+    };
+
+    // Pick this turn's model: the one switched to, the channel's own, or sampled when
+    // weighing several
+    let switched = switched_model.lock().await.clone();
+    let turn_model = match (switched, channel_models.get(&channel_id), &model_weights) {
+        (Some(switched), _, _) => switched,
+        (None, Some(channel_model), _) => channel_model.clone(),
+        (None, None, Some(weights)) => {
+            let turn_model = weights.sample(&mut *rng.lock().await).to_string();
+            info!("Using model {} for this turn", turn_model);
+            turn_model
+        }
+        (None, None, None) => model.to_string(),
+    };
+
+    // Prepare the OpenAI request
+    let mut request = mini_openai::ChatCompletions {
+        messages,
+        model: turn_model,
+        seed: request_seed,
+        n: (processor.choices.n > 1).then_some(processor.choices.n),
+        stop: if stop.is_empty() {
+            None
+        } else {
+            Some(mini_openai::Stop::Array(stop.clone()))
+        },
+        response_format: response_schema
+            .then(|| mini_openai::ResponseFormat::JsonSchema(structured_reply_schema())),
+        ..Default::default()
+    };
+
+    // Drop the lock to avoid holding it during the API request
+    let history_len = channel_history.len();
+    drop(channel_history);
+    history_changed(history_saves.as_ref());
+
+    // Bots can bounce the same line between them, so don't answer lines just said by anyone.
+    // Every line is noted, not just up to the first that is new
+    if let Some(window) = processor.dedup_window {
+        let now = Instant::now();
+        let repeated = turn
+            .messages
+            .iter()
+            .filter(|(_, msg)| recent_lines.repeated(&channel, msg, window, now))
+            .count();
+        if !prompted && repeated == turn.messages.len() {
+            debug!("Turn repeats lines said in the last {:?}, skipping reply", window);
+            return false;
+        }
+    }
+
+    if processor.referee_nick.is_some() && !directed {
+        debug!("The referee hasn't given us the turn in {}, keeping context", channel);
+        return false;
+    }
+
+    // A human spoke, so they have the floor for a while and bots talking among themselves
+    // wait their turn
+    if let Some(human_floor) = processor.human_floor {
+        let is_peer = |nick: &str| peers.iter().any(|peer| same_nick(peer, nick));
+        let mut human_floors = human_floors.lock().unwrap();
+        if turn.messages.iter().any(|(from, _)| !is_peer(from)) {
+            human_floors.insert(channel_id.clone(), Instant::now() + human_floor);
+        } else if human_floors.get(&channel_id).is_some_and(|until| Instant::now() < *until) {
+            debug!("A human has the floor in {}, skipping reply", channel);
+            return false;
+        }
+    }
+
+    // Skip processing if not a leader and there are fewer than 2 messages in history,
+    // counted without system notes and examples so those don't make a follower start
+    if !leader && !directed && history_len < 2 {
+        info!("Skipping first message");
+        return false;
+    }
+
+    // While a peer is away, don't talk to the empty room unless someone addresses us
+    let peer_away = departed_peers
+        .lock()
+        .await
+        .get(&channel_id)
+        .is_some_and(|gone| !gone.is_empty());
+    if peer_away && !directed && !turn.mentions(None, nickname, triggers.mention_strict) {
+        debug!("A peer has left {}, skipping reply", channel);
+        return false;
+    }
+
+    // The message is already in history as context, but only questions get a reply
+    let asked = turn.messages.iter().any(|(_, msg)| looks_like_question(msg));
+    if triggers.questions_only && !prompted && !asked {
+        debug!("Not a question, skipping reply");
+        return false;
+    }
+
+    // "lol" and "k" aren't worth an API call, unless they're meant for us
+    let trivial = triggers.min_length.is_some_and(|min| {
+        turn.messages.iter().all(|(_, msg)| msg.trim().chars().count() < min)
+    });
+    if trivial && !prompted && !turn.mentions(None, nickname, triggers.mention_strict) {
+        debug!("Turn is too short to reply to, keeping it as context");
+        return false;
+    }
+
+    // Some things are never worth engaging with
+    let suppressed_by = triggers.suppress_if_contains.iter().find(|token| {
+        turn.messages.iter().any(|(_, msg)| msg.to_lowercase().contains(token.as_str()))
+    });
+    if let Some(token) = suppressed_by {
+        debug!("Turn contains {:?}, keeping it as context without replying", token);
+        return false;
+    }
+
+    if processor.paused.load(Ordering::SeqCst) {
+        debug!("Paused, keeping the message as context");
+        return false;
+    }
+
+    // Just arrived, so get a feel for the room first
+    let joined_at = processor.joined_at.lock().unwrap().get(&channel_id).copied();
+    let settling = processor
+        .startup_grace
+        .zip(joined_at)
+        .is_some_and(|(grace, joined_at)| joined_at.elapsed() < grace);
+    if settling {
+        debug!("Just joined {}, keeping the message as context", channel);
+        return false;
+    }
+
+    // Where the reply goes, which a --reply-route can make somewhere else
+    let route = processor.reply_routes.iter().find(|route| route.matches(&turn));
+    let target = route.map_or_else(|| channel.clone(), |route| route.target.clone());
+    if target != channel {
+        debug!("Routing the reply to a turn in {} to {}", channel, target);
+    }
+
+    if processor.muted.contains(&target) {
+        debug!("Muted in {}, keeping the message as context", target);
+        return false;
+    }
+
+    // Present in the channel, but only to listen
+    if respond_channels.as_ref().is_some_and(|allowed| !allowed.contains(&channel_id)) {
+        debug!("Not responding in {}, keeping the message as context", channel);
+        return false;
+    }
+
+    // Keep the message as context but don't call the API while it is failing
+    if !breaker.lock().unwrap().allow(Instant::now()) {
+        debug!("API requests paused after repeated failures, skipping reply");
+        return false;
+    }
+
+    // Sum up the older history again when it's time, without holding up the reply
+    if let Some(every) = processor.summary.rolling {
+        start_rolling_summary(processor, &channel, every).await;
+    }
+
+    // Let the channel know we're working on it if the model is slow, but not every turn
+    let placeholder_due = last_thinking
+        .lock()
+        .unwrap()
+        .get(&channel_id)
+        .is_none_or(|posted: &Instant| posted.elapsed() >= thinking.interval);
+    let placeholder = thinking.message.clone().filter(|_| placeholder_due).map(|message| {
+        let output = output.clone();
+        let target = target.clone();
+        let after = thinking.after;
+        tokio::spawn(async move {
+            time::sleep(after).await;
+            if let Err(e) = output.send(&target, &message) {
+                error!("Failed to send thinking message: {}", e);
+            }
+        })
+    });
+
+    // Send the request to OpenAI, backing off and retrying while rate limited
+    let llm_started = Instant::now();
+    let mut attempt = 0;
+    let response = loop {
+        match llm.served_chat_completions(&request).await {
+            Ok(served) => break Some(served),
+            Err(e) if is_rate_limited(&e) && attempt < rate_limit.retries => {
+                let Some(delay) =
+                    rate_limit_wait(&e, attempt, rate_limit.delay, rate_limit.max_delay)
+                else {
+                    error!("Rate limited by the API for longer than we wait: {}", e);
+                    break None;
+                };
+                attempt += 1;
+                warn!(
+                    "Rate limited by the API, retrying in {:?} ({}/{})",
+                    delay, attempt, rate_limit.retries
+                );
+                time::sleep(delay).await;
+            }
+            Err(e) => {
+                error!("OpenAI API request failed: {}", e);
+                break None;
+            }
+        }
+    };
+
+    let llm_took = llm_started.elapsed();
+
+    // Either the placeholder went out while we waited, or it's no longer needed
+    if let Some(placeholder) = placeholder {
+        if placeholder.is_finished() {
+            last_thinking.lock().unwrap().insert(channel_id.clone(), Instant::now());
+        } else {
+            placeholder.abort();
+        }
+    }
+    let Some((mut response, mut served_by)) = response else {
+        breaker.lock().unwrap().record_failure(Instant::now());
+        processor.stats.api_failures.fetch_add(1, Ordering::Relaxed);
+        // Don't look dead to the channel during an outage, if a fallback is set
+        if !fallback_reply.is_empty() {
+            send_text(output, &target, fallback_reply, reply_settings.max_line_bytes).await;
+            if let Some(transcript) = &transcript {
+                transcript.record(now_ms(), &target, nickname, fallback_reply).await;
+            }
+        }
+        return false;
+    };
+    breaker.lock().unwrap().record_success();
+    if let Some(usage) = &served_by.usage {
+        processor.stats.record_usage(usage);
+    }
+
+    debug!("{:#?}", response);
+    info!(
+        channel = %channel,
+        model = served_by.model.as_deref().unwrap_or("unknown"),
+        provider = served_by.provider.as_deref().unwrap_or("unknown"),
+        system_fingerprint = served_by.system_fingerprint.as_deref().unwrap_or("none"),
+        "Served by"
+    );
+
+    // Extract and preserve the reply from OpenAI's response without stripping newlines or backticks
+    let candidates = response.choices.iter().map(|choice| choice.message.content.clone()).collect();
+    let mut reply = choose_reply(processor, &request.messages, candidates)
+        .await
+        .unwrap_or_else(|| "No response from OpenAI.".to_string());
+
+    // A refusal kept as context would have the conversation keep tripping over it
+    if is_refusal(processor, &reply).await {
+        warn!("The model refused to reply in {}: {}", channel, reply);
+        if let Some(transcript) = &transcript {
+            transcript.record_refusal(&target, nickname, &reply, &served_by).await;
+        }
+        match processor.refusal.on_refusal {
+            OnRefusal::Skip => return false,
+            OnRefusal::Substitute => reply = processor.refusal.substitute.clone(),
+            OnRefusal::Retry => {
+                request.messages.push(mini_openai::Message {
+                    content: processor.refusal.reframe.clone(),
+                    role: mini_openai::ROLE_SYSTEM.to_string(),
+                });
+                let retried = match llm.served_chat_completions(&request).await {
+                    Ok(retried) => retried,
+                    Err(e) => {
+                        error!("Asking again after a refusal failed: {}", e);
+                        return false;
+                    }
+                };
+                (response, served_by) = retried;
+                if let Some(usage) = &served_by.usage {
+                    processor.stats.record_usage(usage);
+                }
+                let candidates =
+                    response.choices.iter().map(|choice| choice.message.content.clone()).collect();
+                reply = choose_reply(processor, &request.messages, candidates)
+                    .await
+                    .unwrap_or_default();
+                if reply.is_empty() || is_refusal(processor, &reply).await {
+                    info!("Still refused after asking again in {}, skipping the turn", channel);
+                    if let Some(transcript) = &transcript {
+                        transcript.record_refusal(&target, nickname, &reply, &served_by).await;
+                    }
+                    return false;
+                }
+            }
+        }
+    }
+
+    // Split off hidden reasoning, which the model sees again but the channel never does
+    let mut remembered = None;
+    if let Some(delimiter) = &reply_settings.hidden_delimiter {
+        match reply.split_once(delimiter.as_str()) {
+            Some((hidden, public)) => {
+                debug!("Hidden reasoning: {}", hidden.trim());
+                remembered = Some(reply.clone());
+                reply = public.trim().to_string();
+            }
+            None => debug!("No hidden delimiter in the reply, sending all of it"),
+        }
+    }
+
+    // Act on structured turns, treating anything that doesn't parse as plain text
+    let mut emote = false;
+    if response_schema {
+        match serde_json::from_str::<StructuredReply>(&reply) {
+            Ok(structured) => {
+                debug!("Structured reply: {:?}", structured);
+                match structured.action {
+                    ReplyAction::Ignore => return false,
+                    ReplyAction::Emote => emote = true,
+                    ReplyAction::Say => {}
+                }
+                reply = structured.text;
+            }
+            Err(e) => warn!("Malformed structured reply, sending as text: {}", e),
+        }
+    }
+
+    let words: Vec<&str> = reply.split_whitespace().collect();
+    if words.first().map(|w| w.trim_matches(|c| c == '.' || c == ',').to_lowercase()) == Some("ignore".to_lowercase()) {
+        return false;
+    }
+
+    debug!("{:#?}", response.choices.first());
+
+    // The channel already shows who's talking, and the history gets the prefix added back
+    if reply_settings.strip_own_name {
+        if let Some(rest) = strip_nick_prefix(&reply, nickname).filter(|rest| !rest.is_empty()) {
+            reply = rest.to_string();
+        }
+    }
+
+    let mut cleaned = clean_reply(&reply, reply_settings.format);
+
+    // Introduce ourselves the first time someone mentions us
+    if let Some(introduction) = &introduction {
+        let mentioned = turn.mentions(Some(&sender), nickname, triggers.mention_strict);
+        if mentioned && greeted.lock().await.insert(fold_nick(&sender)) {
+            cleaned = format!("{}\n{}", introduction, cleaned);
+        }
+    }
+
+    // Split the cleaned reply into chunks that fit, each leaving `reserve` bytes free for a
+    // chunk counter
+    let address = address_sender.then_some(sender.as_str());
+    let prefix_bytes = reply_settings.prefix.as_ref().map_or(0, |prefix| prefix.len() + 1);
+    let chunk_reply = |reserve| {
+        let reserve = prefix_bytes + reserve;
+        chunk_reply(&cleaned, address, &target, reply_settings.max_line_bytes, emote, reserve)
+    };
+    let mut reply_chunks = chunk_reply(0);
+
+    // Make room for the counters, chunking again whenever they push the total up a digit
+    if reply_settings.number_chunks {
+        let mut reserve = 0;
+        while reply_chunks.len() > 1 {
+            let needed = chunk_counter(reply_chunks.len(), reply_chunks.len()).len();
+            if needed <= reserve {
+                break;
+            }
+            reserve = needed;
+            reply_chunks = chunk_reply(reserve);
+        }
+    }
+
+    // Back from being away now that we have something to say
+    let mut presence_guard = presence.lock().await;
+    presence_guard.last_reply = Instant::now();
+    if presence_guard.away {
+        info!("Replying, no longer away");
+        if let Err(e) = output.set_away(None) {
+            error!("Failed to clear away status: {}", e);
+        }
+        presence_guard.away = false;
+    }
+    drop(presence_guard);
+
+    // Post a link instead of flooding the channel with a long reply
+    if let Some(paste_url) = &send.paste_url {
+        if reply_chunks.len() > send.paste_threshold {
+            match upload_paste(http, paste_url, &cleaned).await {
+                Ok(link) => {
+                    info!("Reply of {} lines pasted to {}", reply_chunks.len(), link);
+                    reply_chunks = vec![if address_sender {
+                        format!("{}: {}", sender, link)
+                    } else {
+                        link
+                    }];
+                    emote = false;
+                }
+                Err(e) => error!("Failed to paste reply, sending it inline: {}", e),
+            }
+        }
+    }
+
+    if reply_settings.number_chunks {
+        reply_chunks = number_reply_chunks(reply_chunks);
+    }
+    if let Some(prefix) = &reply_settings.prefix {
+        for chunk in &mut reply_chunks {
+            *chunk = format!("{} {}", prefix, chunk);
+        }
+    }
+
+    let send_started = Instant::now();
+    let lines: Vec<_> = reply_chunks
+        .into_iter()
+        .map(|text| UnsentLine {
+            channel: target.clone(),
+            text,
+            emote,
+        })
+        .collect();
+    if let Some(window) = processor.dedup_window {
+        for line in &lines {
+            recent_lines.repeated(&target, &line.text, window, Instant::now());
+        }
+    }
+    // A rate limited target's lines go out from its own queue, without holding up the turn
+    let mut lines = match processor.send_queues.rate(&target) {
+        Some(rate) => {
+            processor.send_queues.enqueue(processor, &target, rate, lines);
+            Vec::new()
+        }
+        None => lines,
+    }
+    .into_iter();
+    while let Some(line) = lines.next() {
+        // Rather than leave a gap in the middle of the reply, the rest is held back with this
+        // line and we reconnect. The reply still goes to the transcript below, and the log
+        // keeps the text
+        let (retries, retry_delay) = (send.retries, send.retry_delay);
+        if let Err(e) = send_with_retries(output, &line, retries, retry_delay).await {
+            error!("Failed to send message chunk: {}: {}", e, line.text);
+            let held_back = std::iter::once(line).chain(lines);
+            if send.resend_failed {
+                unsent.lock().await.extend(held_back);
+            } else {
+                warn!("Dropped the last {} lines of the reply", held_back.count());
+            }
+            processor.send_failed.notify_one();
+            break;
+        }
+        // Introduce a small delay to prevent rapid sending
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    info!(
+        channel = %channel,
+        buffered_ms = buffered.as_millis() as u64,
+        llm_ms = llm_took.as_millis() as u64,
+        send_ms = send_started.elapsed().as_millis() as u64,
+        "Turn timing"
+    );
+
+    if let Some(transcript) = &transcript {
+        transcript.record_reply(&target, nickname, &reply, &served_by).await;
+    }
+
+    // Hand the reply to the external command without holding up the next turn
+    if let Some(command) = &on_reply_command {
+        let command = command.clone();
+        let event = ReplyEvent {
+            timestamp_ms: now_ms(),
+            channel: target.clone(),
+            sender: sender.clone(),
+            model: request.model.clone(),
+            emote,
+            reply: reply.clone(),
+        };
+        tokio::spawn(async move { run_reply_command(&command, &event).await });
+    }
+
+    if processor.quotes.on_quote.is_some() {
+        processor.recent_replies.record(&target, &reply);
+    }
+
+    // Add the response to the channel's history
+    let mut channel_history = channel_lock.lock().await;
+    channel_history.push(format!("{}: {}", nickname, remembered.unwrap_or(reply)));
+
+    // Optionally, log the updated history
+    debug!("{:#?}", channel_history);
+    drop(channel_history);
+    history_changed(history_saves.as_ref());
+
+    processor.stats.replies.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use crate::test_support::*;
+
+    #[tokio::test]
+    async fn answers_a_turn_from_the_backend() {
+        let backend = Arc::new(MockBackend {
+            reply: "hello there".to_string(),
+            ..Default::default()
+        });
+        let processor = test_processor(Arc::clone(&backend));
+        let history = processor.history.get("#rust").unwrap();
+        history.lock().await.push("bot: earlier".to_string());
+
+        assert!(process_turn(&processor, turn(&[("alice", "hi"), ("carol", "hey bot")])).await);
+
+        let request = backend.requests.lock().unwrap()[0].clone();
+        let user = mini_openai::ROLE_USER.to_string();
+        let assistant = mini_openai::ROLE_ASSISTANT.to_string();
+        assert_eq!(
+            request[request.len() - 3..],
+            [
+                (assistant, "bot: earlier".to_string()),
+                (user.clone(), "alice: hi".to_string()),
+                (user, "carol: hey bot".to_string()),
+            ]
+        );
+        assert_eq!(
+            *history.lock().await,
+            ["bot: earlier", "alice: hi", "carol: hey bot", "bot: hello there"]
+        );
+    }
+
+    #[tokio::test]
+    async fn strips_our_own_name_from_replies() {
+        let backend = Arc::new(MockBackend {
+            reply: "Bot: hello there".to_string(),
+            ..Default::default()
+        });
+        let mut processor = test_processor(backend);
+        processor.reply.strip_own_name = true;
+
+        assert!(process_turn(&processor, turn(&[("alice", "hi")])).await);
+        let history = processor.history.get("#rust").unwrap();
+        assert_eq!(history.lock().await.last().unwrap(), "bot: hello there");
+    }
+
+    #[tokio::test]
+    async fn output_prefix_is_not_remembered() {
+        let backend = Arc::new(MockBackend {
+            reply: "hello there".to_string(),
+            ..Default::default()
+        });
+        let mut processor = test_processor(backend);
+        processor.reply.prefix = Some("[mock]".to_string());
+        let file = TempFile::new("output.txt");
+        processor.output = file.output();
+
+        assert!(process_turn(&processor, turn(&[("alice", "hi")])).await);
+        assert_eq!(file.read(), "#rust <bot> [mock] hello there\n");
+        let history = processor.history.get("#rust").unwrap();
+        assert_eq!(history.lock().await.last().unwrap(), "bot: hello there");
+    }
+
+    #[tokio::test]
+    async fn peers_wait_while_a_human_has_the_floor() {
+        let backend = Arc::new(MockBackend {
+            reply: "hello".to_string(),
+            ..Default::default()
+        });
+        let mut processor = test_processor(Arc::clone(&backend));
+        processor.human_floor = Some(Duration::from_secs(60));
+
+        assert!(process_turn(&processor, turn(&[("alice", "hi all")])).await);
+        assert!(!process_turn(&processor, turn(&[("PeerBot", "hi alice")])).await);
+        assert!(process_turn(&processor, turn(&[("alice", "and you?")])).await);
+        assert_eq!(backend.requests.lock().unwrap().len(), 2);
+
+        processor.human_floors.lock().unwrap().clear();
+        assert!(process_turn(&processor, turn(&[("PeerBot", "hi again")])).await);
+    }
+
+    #[tokio::test]
+    async fn short_messages_need_a_mention() {
+        let backend = Arc::new(MockBackend::default());
+        let mut processor = test_processor(Arc::clone(&backend));
+        processor.triggers.min_length = Some(4);
+
+        assert!(!process_turn(&processor, turn(&[("alice", " lol ")])).await);
+        assert!(backend.requests.lock().unwrap().is_empty());
+        assert!(process_turn(&processor, turn(&[("alice", "bot?")])).await);
+        assert!(process_turn(&processor, turn(&[("alice", "k"), ("alice", "why so?")])).await);
+    }
+
+    #[tokio::test]
+    async fn echoed_lines_get_no_reply() {
+        let backend = Arc::new(MockBackend {
+            reply: "same to you".to_string(),
+            ..Default::default()
+        });
+        let mut processor = test_processor(Arc::clone(&backend));
+        processor.dedup_window = Some(Duration::from_secs(60));
+
+        assert!(process_turn(&processor, turn(&[("alice", "good morning")])).await);
+        assert!(!process_turn(&processor, turn(&[("peerbot", "good morning")])).await);
+        assert!(!process_turn(&processor, turn(&[("peerbot", "same to you")])).await);
+        let partly_new = turn(&[("alice", "good morning"), ("alice", "hm")]);
+        assert!(process_turn(&processor, partly_new).await);
+        assert_eq!(backend.requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn transcript_records_who_served_replies() {
+        let backend = Arc::new(MockBackend {
+            reply: "hello".to_string(),
+            ..Default::default()
+        });
+        let mut processor = test_processor(backend);
+        let file = TempFile::new("served.jsonl");
+        processor.transcript = Some(Arc::new(Transcript::open(&file.path, None).unwrap()));
+
+        assert!(process_turn(&processor, turn(&[("alice", "hi")])).await);
+        let entries = read_transcript(&file.path).unwrap();
+        let reply = entries.last().unwrap();
+        assert_eq!(reply.message, "hello");
+        assert_eq!(reply.served_by.as_ref().unwrap().model.as_deref(), Some("mock"));
+    }
+
+    #[tokio::test]
+    async fn turns_wait_for_the_one_queued_before() {
+        let mut processor = test_processor(Arc::default());
+        let first = processor.queue_turn(&turn(&[("alice", "one")]));
+        let second = processor.queue_turn(&turn(&[("bob", "two")]));
+        first.wait().await;
+        assert!(time::timeout(Duration::from_millis(50), second.wait()).await.is_err());
+        drop(first);
+        assert!(time::timeout(Duration::from_millis(50), second.wait()).await.is_ok());
+
+        processor.turn_order = TurnOrder::Sender;
+        let _alice = processor.queue_turn(&turn(&[("alice", "one")]));
+        let bob = processor.queue_turn(&turn(&[("bob", "two")]));
+        assert!(time::timeout(Duration::from_millis(50), bob.wait()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn routed_replies_go_to_their_target() {
+        let backend = Arc::new(MockBackend {
+            reply: "noted".to_string(),
+            ..Default::default()
+        });
+        let mut processor = test_processor(backend);
+        processor.reply_routes = vec![ReplyRoute::parse("#rust,contains=outage>#ops").unwrap()];
+        let file = TempFile::new("routed.txt");
+        processor.output = file.output();
+
+        assert!(process_turn(&processor, turn(&[("alice", "is there an OUTAGE?")])).await);
+        assert!(process_turn(&processor, turn(&[("alice", "all good now")])).await);
+        assert_eq!(file.read(), "#ops <bot> noted\n#rust <bot> noted\n");
+        let history = processor.history.get("#rust").unwrap();
+        assert_eq!(history.lock().await.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn follower_waits_for_more_context() {
+        let backend = Arc::new(MockBackend::default());
+        let mut processor = test_processor(Arc::clone(&backend));
+        processor.leader = false;
+
+        assert!(!process_turn(&processor, turn(&[("alice", "hi")])).await);
+        assert!(backend.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn builds_messages_around_the_history() {
+        let mut processor = test_processor(Arc::default());
+        processor.system_prompt = Some("Be brief".to_string());
+        processor.examples = vec![mini_openai::Message {
+            content: "alice: ping".to_string(),
+            role: mini_openai::ROLE_USER.to_string(),
+        }];
+        let history = ["alice: hi".to_string(), "bot: hello".to_string(), "garbled".to_string()];
+
+        let messages = build_messages(&processor, "#rust", &history, true).await;
+        let messages: Vec<(&str, &str)> =
+            messages.iter().map(|m| (m.role.as_str(), m.content.as_str())).collect();
+        assert_eq!(messages[0], (mini_openai::ROLE_SYSTEM, "Be brief"));
+        assert_eq!(
+            messages[2..],
+            [
+                (mini_openai::ROLE_USER, "alice: ping"),
+                (mini_openai::ROLE_USER, "alice: hi"),
+                (mini_openai::ROLE_ASSISTANT, "bot: hello"),
+                (mini_openai::ROLE_USER, "garbled"),
+                (mini_openai::ROLE_SYSTEM, REVIVE_PROMPT),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn channel_personas_replace_the_global_one() {
+        let dir = env::temp_dir().join(format!("personas-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let persona = r#"{"system_prompt": "You are a pirate"}"#;
+        std::fs::write(dir.join("rust.json"), persona).unwrap();
+        let channels: Vec<ChannelConfig> =
+            ["#Rust", "#go"].iter().map(|c| ChannelConfig::parse(c).unwrap()).collect();
+        let personas = read_personas(&dir, &channels);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut processor = test_processor(Arc::default());
+        processor.system_prompt = Some("Be brief".to_string());
+        processor.examples = vec![mini_openai::Message {
+            content: "alice: ping".to_string(),
+            role: mini_openai::ROLE_USER.to_string(),
+        }];
+        processor.personas = personas.unwrap();
+        assert_eq!(processor.personas.len(), 1);
+
+        let messages = build_messages(&processor, "#rust", &[], false).await;
+        assert_eq!(messages[0].content, "You are a pirate");
+        assert_eq!(messages.last().unwrap().content, "alice: ping");
+        let messages = build_messages(&processor, "#go", &[], false).await;
+        assert_eq!(messages[0].content, "Be brief");
+    }
+
+    #[tokio::test]
+    async fn merges_consecutive_messages_with_the_same_role() {
+        let mut processor = test_processor(Arc::default());
+        processor.context.merge_same_role = true;
+        processor.context.turn_separator = "\n---\n".to_string();
+        let history = ["alice: hi".to_string(), "carol: yo".to_string(), "bot: hey".to_string()];
+
+        let messages = build_messages(&processor, "#rust", &history, false).await;
+        let roles: Vec<&str> = messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(
+            roles,
+            [mini_openai::ROLE_SYSTEM, mini_openai::ROLE_USER, mini_openai::ROLE_ASSISTANT]
+        );
+        assert_eq!(messages[1].content, "alice: hi\n---\ncarol: yo");
+    }
+
+    #[test]
+    fn referee_goes_round_the_peers() {
+        let peers = ["a".to_string(), "b".to_string(), "c".to_string()];
+        let none = HashSet::new();
+        assert_eq!(next_speaker(&peers, None, "alice", &none), Some("a"));
+        assert_eq!(next_speaker(&peers, Some("a"), "a", &none), Some("b"));
+        assert_eq!(next_speaker(&peers, Some("c"), "c", &none), Some("a"));
+        // Whoever just spoke doesn't go again, nor does a peer that left
+        assert_eq!(next_speaker(&peers, Some("c"), "A", &none), Some("b"));
+        let away = HashSet::from(["b".to_string()]);
+        assert_eq!(next_speaker(&peers, Some("a"), "alice", &away), Some("c"));
+        assert_eq!(next_speaker(&peers[..1], Some("a"), "a", &none), Some("a"));
+    }
+
+    #[tokio::test]
+    async fn replies_only_when_the_referee_says_so() {
+        let backend = Arc::new(MockBackend {
+            reply: "my turn".to_string(),
+            ..Default::default()
+        });
+        let mut processor = test_processor(Arc::clone(&backend));
+        processor.referee_nick = Some("ref".to_string());
+
+        let turn_for_peer = turn(&[("alice", "what now?"), ("ref", "@peerbot your turn")]);
+        assert!(!process_turn(&processor, turn_for_peer).await);
+        assert!(backend.requests.lock().unwrap().is_empty());
+        assert!(process_turn(&processor, turn(&[("ref", "@bot your turn")])).await);
+
+        let history = processor.history.get("#rust").unwrap();
+        assert_eq!(*history.lock().await, ["alice: what now?", "bot: my turn"]);
+
+        processor.referee_nick = None;
+        processor.referee = true;
+        let file = TempFile::new("referee.txt");
+        processor.output = file.output();
+        assert!(process_turn(&processor, turn(&[("alice", "go on")])).await);
+        assert_eq!(file.read(), "#rust <bot> @peerbot your turn\n");
+        assert_eq!(backend.requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn context_stats_describe_the_channel() {
+        let mut processor = test_processor(Arc::default());
+        processor.context.context_stats = true;
+        let nicks = BTreeMap::from([
+            ("alice".to_string(), "Alice".to_string()),
+            ("bot".to_string(), "bot".to_string()),
+        ]);
+        processor.participants.lock().await.insert("#rust".to_string(), nicks);
+        processor.message_rate.record("#Rust", 5, Instant::now());
+
+        let messages = build_messages(&processor, "#rust", &[], false).await;
+        let stats = messages.iter().find(|m| m.content.starts_with("Channel stats:")).unwrap();
+        assert_eq!(
+            stats.content,
+            "Channel stats: 2 participants, 5.0 messages a minute lately, running for 0 minutes"
+        );
+    }
+
+    #[tokio::test]
+    async fn summarizes_each_channel_on_exit() {
+        let backend = Arc::new(MockBackend {
+            reply: "They argued about lifetimes.".to_string(),
+            ..Default::default()
+        });
+        let mut processor = test_processor(Arc::clone(&backend));
+        let file = TempFile::new("summary.txt");
+        processor.summary.file = Some(file.path.clone());
+        let entries = ["alice: 'static?".to_string(), "bot: not always".to_string()];
+        processor.history.channel("#rust").lock().await.extend(entries);
+        processor.history.channel("#empty");
+
+        post_summaries(&processor).await;
+        assert_eq!(file.read(), "#rust\nThey argued about lifetimes.\n\n");
+        let requests = backend.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0][1].1, "alice: 'static?\nbot: not always");
+    }
+
+    #[tokio::test]
+    async fn holds_back_the_rest_of_a_reply_that_failed_to_send() {
+        let backend = Arc::new(MockBackend {
+            reply: "first line\nsecond line".to_string(),
+            ..Default::default()
+        });
+        let mut processor = test_processor(backend);
+        processor.output = Output::Irc(Arc::default());
+        processor.send.resend_failed = true;
+        processor.send.retries = 1;
+
+        process_turn(&processor, turn(&[("alice", "hi")])).await;
+        let unsent = processor.unsent.lock().await;
+        let texts: Vec<&str> = unsent.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(texts, ["first line", "second line"]);
+        // Kept for the connection even though nothing was waiting when the send failed
+        let reconnect = processor.send_failed.notified();
+        assert!(time::timeout(Duration::from_millis(10), reconnect).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn older_history_thins_out() {
+        let history: Vec<String> = (0..14).map(|i| format!("alice: e{}", i)).collect();
+        let kept: Vec<&str> = decay_history(&history, 4, 2.0)
+            .iter()
+            .map(|entry| entry.trim_start_matches("alice: "))
+            .collect();
+        assert_eq!(kept, ["e1", "e5", "e7", "e9", "e10", "e11", "e12", "e13"]);
+        assert_eq!(decay_history(&history, 4, 1.0).len(), 14);
+
+        let mut processor = test_processor(Arc::default());
+        processor.context.decay_after = Some(4);
+        let messages = build_messages(&processor, "#rust", &history, false).await;
+        assert!(messages.iter().any(|m| m.content.contains("6 of its messages left out")));
+        assert_eq!(messages.last().unwrap().content, "alice: e13");
+    }
+
+    #[tokio::test]
+    async fn recognizes_our_replies_quoted_back() {
+        let backend = Arc::new(MockBackend {
+            reply: "Lifetimes are checked at compile time.".to_string(),
+            ..Default::default()
+        });
+        let mut processor = test_processor(Arc::clone(&backend));
+        processor.quotes.on_quote = Some(QuotedReplies::Skip);
+        assert!(process_turn(&processor, turn(&[("alice", "when?")])).await);
+
+        let quote = "<bot> lifetimes are CHECKED at compile time";
+        assert!(!process_turn(&processor, turn(&[("bob", quote)])).await);
+        assert_eq!(backend.requests.lock().unwrap().len(), 1);
+        assert!(process_turn(&processor, turn(&[("bob", "checked at runtime?")])).await);
+
+        processor.quotes.on_quote = Some(QuotedReplies::Tag);
+        process_turn(&processor, turn(&[("bob", quote)])).await;
+        let history = processor.history.get("#rust").unwrap();
+        let tagged = format!("bob: (quoting bot) {}", quote);
+        assert!(history.lock().await.contains(&tagged));
+        assert!(!RecentReplies::default().quoted_in("#rust", quote, 0.8));
+    }
+
+    #[tokio::test]
+    async fn listens_for_a_while_after_joining() {
+        let backend = Arc::new(MockBackend::default());
+        let mut processor = test_processor(Arc::clone(&backend));
+        processor.startup_grace = Some(Duration::from_secs(60));
+        assert!(process_turn(&processor, turn(&[("alice", "offline, no join")])).await);
+
+        processor.joined_at.lock().unwrap().insert("#rust".to_string(), Instant::now());
+        assert!(!process_turn(&processor, turn(&[("alice", "welcome!")])).await);
+        let history = processor.history.get("#rust").unwrap();
+        assert!(history.lock().await.contains(&"alice: welcome!".to_string()));
+
+        let joined = Instant::now() - Duration::from_secs(61);
+        processor.joined_at.lock().unwrap().insert("#rust".to_string(), joined);
+        assert!(process_turn(&processor, turn(&[("alice", "hello?")])).await);
+    }
+
+    #[tokio::test]
+    async fn handles_refusals() {
+        let backend = Arc::new(MockBackend {
+            reply: "Sorry, I can't help with that.".to_string(),
+            ..Default::default()
+        });
+        let mut processor = test_processor(Arc::clone(&backend));
+        processor.refusal.phrases = vec!["can't help with".to_string()];
+        let history = processor.history.get("#rust").unwrap();
+
+        assert!(!process_turn(&processor, turn(&[("alice", "hi bot")])).await);
+        assert_eq!(*history.lock().await, ["alice: hi bot"]);
+
+        processor.refusal.on_refusal = OnRefusal::Retry;
+        assert!(!process_turn(&processor, turn(&[("alice", "still there?")])).await);
+        let requests = backend.requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 3);
+        let reframe = (mini_openai::ROLE_SYSTEM.to_string(), DEFAULT_REFUSAL_REFRAME.to_string());
+        assert_eq!(requests[2].last(), Some(&reframe));
+        assert_eq!(history.lock().await.len(), 2);
+
+        processor.refusal.on_refusal = OnRefusal::Substitute;
+        assert!(process_turn(&processor, turn(&[("alice", "hello?")])).await);
+        let expected = format!("bot: {}", DEFAULT_REFUSAL_SUBSTITUTE);
+        assert_eq!(history.lock().await.last(), Some(&expected));
+    }
+
+    /// Waits for the rolling summaries being made in the background to land
+    async fn summaries_landed(processor: &Processor) {
+        while processor.rolling_summaries.lock().unwrap().values().any(|s| s.in_flight) {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// The conversations sent to be summed up so far
+    fn summary_requests(backend: &MockBackend) -> Vec<String> {
+        let requests = backend.requests.lock().unwrap();
+        let summaries = requests.iter().filter(|r| r[0].1 == DEFAULT_SUMMARY_PROMPT);
+        summaries.map(|request| request[1].1.clone()).collect()
+    }
+
+    #[tokio::test]
+    async fn pins_a_rolling_summary() {
+        let backend = Arc::new(MockBackend {
+            reply: "they talked".to_string(),
+            ..Default::default()
+        });
+        let mut processor = test_processor(Arc::clone(&backend));
+        processor.summary.rolling = Some(2);
+        processor.summary.rolling_window = 2;
+        let history = processor.history.get("#rust").unwrap();
+        for entry in ["alice: one", "bob: two", "alice: three", "bob: four"] {
+            history.lock().await.push(entry.to_string());
+        }
+
+        assert!(process_turn(&processor, turn(&[("alice", "five")])).await);
+        assert!(process_turn(&processor, turn(&[("alice", "six")])).await);
+        summaries_landed(&processor).await;
+        let first = "alice: one\nbob: two\nalice: three\nbob: four\nalice: five";
+        assert_eq!(summary_requests(&backend), [first]);
+
+        assert!(process_turn(&processor, turn(&[("alice", "seven")])).await);
+        let requests = backend.requests.lock().unwrap().clone();
+        let sent: Vec<_> = requests.last().unwrap().iter().map(|(_, c)| c.as_str()).collect();
+        assert!(sent.contains(&"Summary of the conversation so far: they talked"));
+        let verbatim = ["bot: they talked", "alice: six", "bot: they talked", "alice: seven"];
+        assert_eq!(sent[sent.len() - 4..], verbatim);
+        assert!(!sent.contains(&"alice: five"));
+
+        // Only what's new since goes with the summary so far
+        assert!(process_turn(&processor, turn(&[("alice", "eight")])).await);
+        summaries_landed(&processor).await;
+        let second = "Summary of the conversation before this: they talked\n\n\
+            bot: they talked\nalice: six\nbot: they talked\nalice: seven";
+        assert_eq!(summary_requests(&backend), [first, second]);
+
+        processor.generations.advance("#rust");
+        history.lock().await.clear();
+        let messages = build_messages(&processor, "#rust", &["alice: hi".to_string()], false).await;
+        assert!(!messages.iter().any(|m| m.content.starts_with("Summary of")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rolling_summaries_never_pile_up() {
+        let backend = Arc::new(MockBackend {
+            reply: "they talked".to_string(),
+            slow: Some((DEFAULT_SUMMARY_PROMPT.to_string(), Duration::from_secs(60))),
+            ..Default::default()
+        });
+        let mut processor = test_processor(Arc::clone(&backend));
+        processor.summary.rolling = Some(1);
+        processor.summary.rolling_window = 1;
+        let history = processor.history.get("#rust").unwrap();
+        for entry in ["bob: a", "bob: b", "bob: c"] {
+            history.lock().await.push(entry.to_string());
+        }
+
+        // The first summary is still being made when the next is due, so that one is skipped
+        assert!(process_turn(&processor, turn(&[("alice", "one")])).await);
+        assert!(process_turn(&processor, turn(&[("alice", "two")])).await);
+        tokio::task::yield_now().await;
+        assert_eq!(summary_requests(&backend).len(), 1);
+
+        // Or the history is cut to the window until it's done
+        processor.summary.on_backlog = SummaryBacklog::Truncate;
+        assert!(process_turn(&processor, turn(&[("alice", "three")])).await);
+        assert!(process_turn(&processor, turn(&[("alice", "four")])).await);
+        let requests = backend.requests.lock().unwrap().clone();
+        let sent: Vec<_> = requests.last().unwrap().iter().map(|(_, c)| c.as_str()).collect();
+        assert_eq!(sent.last(), Some(&"alice: four"));
+        assert!(!sent.contains(&"alice: three"));
+        assert_eq!(summary_requests(&backend).len(), 1);
+
+        time::sleep(Duration::from_secs(60)).await;
+        summaries_landed(&processor).await;
+        let summaries = processor.rolling_summaries.lock().unwrap();
+        let summary = &summaries["#rust"];
+        assert_eq!(summary.text.as_deref(), Some("they talked"));
+        assert!(!summary.truncating);
+    }
+}
@@ -0,0 +1,403 @@
+//! Chat completion backends and the provider behind them
+
+use futures::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::time::{Duration, Instant};
+use tracing::{info, warn};
+use crate::ChatroomError;
+
+/// OpenAI-compatible API that requests are sent to
+pub(crate) const API_BASE_URL: &str = "https://openrouter.ai/api/v1";
+
+/// A page of the `/models` listing, of which only the IDs are needed
+#[derive(Deserialize)]
+struct ModelList {
+    data: Vec<ModelInfo>,
+}
+
+#[derive(Deserialize)]
+struct ModelInfo {
+    id: String,
+}
+
+/// Fetches the IDs of the models the provider offers, sorted
+pub(crate) async fn list_models(
+    http: &reqwest::Client,
+    api_key: Option<&str>,
+) -> Result<Vec<String>, ChatroomError> {
+    let mut request = http.get(format!("{}/models", API_BASE_URL));
+    if let Some(api_key) = api_key {
+        request = request.bearer_auth(api_key);
+    }
+    let body = request.send().await?.error_for_status()?.text().await?;
+    let models: ModelList = serde_json::from_str(&body)?;
+
+    let mut ids: Vec<String> = models.data.into_iter().map(|model| model.id).collect();
+    ids.sort();
+    Ok(ids)
+}
+
+/// Checks a model name against the provider's listing. When the listing can't be fetched
+/// the name is let through, and a bad one shows up as failed requests instead
+pub(crate) async fn validate_model(
+    http: &reqwest::Client,
+    api_key: Option<&str>,
+    model: &str,
+) -> Result<(), String> {
+    match list_models(http, api_key).await {
+        Ok(ids) if ids.iter().any(|id| id == model) => Ok(()),
+        Ok(_) => Err(format!("Unknown model {}.", model)),
+        Err(e) => {
+            warn!("Couldn't list models to check {}, using it anyway: {}", model, e);
+            Ok(())
+        }
+    }
+}
+
+/// Whether the API rejected a request with HTTP 429 Too Many Requests.
+///
+/// mini_openai only surfaces the status in the error text, so `ProviderClient` appends the
+/// response's `Retry-After` to it too, for `retry_after` to read back
+pub(crate) fn is_rate_limited(e: &mini_openai::Error) -> bool {
+    api_status(e) == Some(429)
+}
+
+/// The HTTP status of an API error, from its text: `429 Too Many Requests` as mini_openai's
+/// blocking client puts it, or `HTTP status client error (429 Too Many Requests) for url ...`
+/// as reqwest does
+fn api_status(e: &mini_openai::Error) -> Option<u16> {
+    let mini_openai::Error::ApiError(msg) = e else {
+        return None;
+    };
+    let msg = msg.split(RETRY_AFTER_NOTE).next()?;
+    let status = match msg.split_once(" (") {
+        Some((prefix, rest)) if prefix.starts_with("HTTP status") => rest,
+        _ => msg,
+    };
+    let code = status.get(..3)?;
+    let followed = status[3..].starts_with(' ') || status.len() == 3;
+    code.parse().ok().filter(|_| followed)
+}
+
+/// How long to wait before retry number `attempt + 1` of a rate limited request: what the API
+/// asked for, or else `delay` doubled per earlier retry, capped at `max`. None when the API
+/// asked for longer than `max`
+pub(crate) fn rate_limit_wait(
+    e: &mini_openai::Error,
+    attempt: u32,
+    delay: Duration,
+    max: Duration,
+) -> Option<Duration> {
+    match retry_after(e) {
+        Some(asked) => (asked <= max).then_some(asked),
+        None => Some(delay.saturating_mul(2u32.saturating_pow(attempt)).min(max)),
+    }
+}
+
+/// Put after an API error's text, followed by the seconds `Retry-After` asked for
+const RETRY_AFTER_NOTE: &str = "; retry after ";
+
+/// How long the API asked us to wait before trying again, if it said
+fn retry_after(e: &mini_openai::Error) -> Option<Duration> {
+    let mini_openai::Error::ApiError(msg) = e else {
+        return None;
+    };
+    let (_, secs) = msg.rsplit_once(RETRY_AFTER_NOTE)?;
+    secs.strip_suffix('s')?.parse().ok().map(Duration::from_secs)
+}
+
+/// Reads a `Retry-After` header, given as seconds or as an HTTP date like
+/// `Sun, 06 Nov 1994 08:49:37 GMT`, into how long to wait from `now`
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let fields: Vec<&str> = value.split_whitespace().collect();
+    let [_, day, month, year, time, "GMT"] = fields[..] else {
+        return None;
+    };
+    const MONTHS: [&str; 12] =
+        ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let month = MONTHS.iter().position(|m| *m == month)? as i64 + 1;
+    let (day, year): (i64, i64) = (day.parse().ok()?, year.parse().ok()?);
+    let mut clock = time.split(':').map(|part| part.parse::<i64>().ok());
+    let (hours, minutes, seconds) = (clock.next()??, clock.next()??, clock.next()??);
+
+    // Days since the Unix epoch of a date in the proleptic Gregorian calendar
+    let shifted_year = if month <= 2 { year - 1 } else { year };
+    let era = shifted_year.div_euclid(400);
+    let year_of_era = shifted_year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let at = days * 86_400 + hours * 3_600 + minutes * 60 + seconds;
+    let now = now.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+    Some(Duration::from_secs(at.saturating_sub(now).max(0) as u64))
+}
+
+/// Pauses API calls after repeated failures so an outage isn't hammered on every turn
+pub(crate) struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    /// Failed requests in a row
+    failures: u32,
+    /// When calls may be tried again, while cooling down
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            failures: 0,
+            open_until: None,
+        }
+    }
+
+    /// Whether a request may be made now. Once the cooldown is over one probe is let through,
+    /// and a failure of that probe starts another cooldown
+    pub(crate) fn allow(&mut self, now: Instant) -> bool {
+        match self.open_until {
+            Some(until) if now < until => false,
+            Some(_) => {
+                info!("API cooldown over, trying again");
+                self.open_until = None;
+                true
+            }
+            None => true,
+        }
+    }
+
+    pub(crate) fn record_success(&mut self) {
+        if self.failures >= self.threshold {
+            info!("API requests are working again");
+        }
+        self.failures = 0;
+    }
+
+    pub(crate) fn record_failure(&mut self, now: Instant) {
+        self.failures += 1;
+        if self.failures >= self.threshold {
+            warn!(
+                "{} API requests failed in a row, pausing requests for {:?}",
+                self.failures, self.cooldown
+            );
+            self.open_until = Some(now + self.cooldown);
+        }
+    }
+}
+
+/// Where chat completions come from, so tests and embedders can stand in for the API
+pub trait ChatBackend: Send + Sync {
+    fn chat_completions<'a>(
+        &'a self,
+        request: &'a mini_openai::ChatCompletions,
+    ) -> future::BoxFuture<'a, Result<mini_openai::ChatCompletionsResponse, mini_openai::Error>>;
+
+    /// Like `chat_completions`, along with who served the completion. Backends that can't
+    /// tell only have the model the response names
+    fn served_chat_completions<'a>(
+        &'a self,
+        request: &'a mini_openai::ChatCompletions,
+    ) -> future::BoxFuture<'a, Result<ServedCompletion, mini_openai::Error>> {
+        Box::pin(self.chat_completions(request).map_ok(|response| {
+            let served_by = ServedBy {
+                model: Some(response.model.clone()),
+                ..Default::default()
+            };
+            (response, served_by)
+        }))
+    }
+}
+
+/// A completion and who served it
+pub type ServedCompletion = (mini_openai::ChatCompletionsResponse, ServedBy);
+
+/// What the provider says about who served a completion. OpenRouter routes each request to
+/// whichever provider it picks, so the same model name can mean different deployments
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ServedBy {
+    /// The model that actually answered, which can differ from the one asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_fingerprint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+/// Tokens a completion took, and what it cost when the provider says (OpenRouter does)
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Usage {
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+}
+
+/// Chat completions straight from the provider's HTTP API, as mini_openai drops everything
+/// in a response but the choices and model
+pub(crate) struct ProviderClient {
+    pub(crate) http: reqwest::Client,
+    pub(crate) api_key: Option<String>,
+}
+
+impl ProviderClient {
+    /// Sends the request the way mini_openai does, with errors it would give
+    async fn request(
+        &self,
+        request: &mini_openai::ChatCompletions,
+    ) -> Result<ServedCompletion, mini_openai::Error> {
+        let body = serde_json::to_string(request).map_err(mini_openai::Error::SerializationError)?;
+        let mut post = self
+            .http
+            .post(format!("{}/chat/completions", API_BASE_URL))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body);
+        if let Some(api_key) = &self.api_key {
+            post = post.bearer_auth(api_key);
+        }
+        let response = post
+            .send()
+            .await
+            .map_err(|e| mini_openai::Error::NetworkError(e.to_string()))?;
+        if let Err(e) = response.error_for_status_ref() {
+            let mut message = e.to_string();
+            let wait = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| parse_retry_after(value, SystemTime::now()));
+            if let Some(wait) = wait {
+                message.push_str(&format!("{}{}s", RETRY_AFTER_NOTE, wait.as_secs()));
+            }
+            return Err(mini_openai::Error::ApiError(message));
+        }
+        let text = response
+            .text()
+            .await
+            .map_err(|e| mini_openai::Error::NetworkError(e.to_string()))?;
+        parse_served_completion(&text)
+    }
+}
+
+/// Reads a completion and who served it from the same response body
+pub(crate) fn parse_served_completion(body: &str) -> Result<ServedCompletion, mini_openai::Error> {
+    let malformed = |e: serde_json::Error| mini_openai::Error::DeserializationError(e.to_string());
+    let response = serde_json::from_str(body).map_err(malformed)?;
+    let served_by = serde_json::from_str(body).map_err(malformed)?;
+    Ok((response, served_by))
+}
+
+impl ChatBackend for ProviderClient {
+    fn chat_completions<'a>(
+        &'a self,
+        request: &'a mini_openai::ChatCompletions,
+    ) -> future::BoxFuture<'a, Result<mini_openai::ChatCompletionsResponse, mini_openai::Error>>
+    {
+        Box::pin(self.request(request).map_ok(|(response, _)| response))
+    }
+
+    fn served_chat_completions<'a>(
+        &'a self,
+        request: &'a mini_openai::ChatCompletions,
+    ) -> future::BoxFuture<'a, Result<ServedCompletion, mini_openai::Error>> {
+        Box::pin(self.request(request))
+    }
+}
+
+impl ChatBackend for mini_openai::Client {
+    fn chat_completions<'a>(
+        &'a self,
+        request: &'a mini_openai::ChatCompletions,
+    ) -> future::BoxFuture<'a, Result<mini_openai::ChatCompletionsResponse, mini_openai::Error>>
+    {
+        Box::pin(mini_openai::Client::chat_completions(self, request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn circuit_breaker_cools_down_and_probes() {
+        let start = Instant::now();
+        let mut breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+
+        breaker.record_failure(start);
+        assert!(breaker.allow(start));
+        breaker.record_failure(start);
+        assert!(!breaker.allow(start + Duration::from_secs(59)));
+
+        // A failed probe pauses again right away, a successful one resets the count
+        assert!(breaker.allow(start + Duration::from_secs(60)));
+        breaker.record_failure(start + Duration::from_secs(60));
+        assert!(!breaker.allow(start + Duration::from_secs(61)));
+        assert!(breaker.allow(start + Duration::from_secs(120)));
+        breaker.record_success();
+        breaker.record_failure(start + Duration::from_secs(120));
+        assert!(breaker.allow(start + Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn reads_who_served_a_completion() {
+        let body = r#"{"id": "gen-1", "object": "chat.completion", "created": 1,
+            "model": "openai/gpt-4o-2024-08-06", "provider": "Azure",
+            "system_fingerprint": "fp_abc", "choices": [{"index": 0,
+            "message": {"role": "assistant", "content": "hi"}, "finish_reason": "stop"}]}"#;
+        let (response, served_by) = parse_served_completion(body).unwrap();
+        assert_eq!(response.choices[0].message.content, "hi");
+        assert_eq!(served_by.model.as_deref(), Some("openai/gpt-4o-2024-08-06"));
+        assert_eq!(served_by.provider.as_deref(), Some("Azure"));
+        assert_eq!(served_by.system_fingerprint.as_deref(), Some("fp_abc"));
+    }
+
+    #[test]
+    fn reads_how_long_a_rate_limit_asks_to_wait() {
+        let now = UNIX_EPOCH + Duration::from_secs(784_111_717);
+        assert_eq!(parse_retry_after(" 120 ", now), Some(Duration::from_secs(120)));
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        assert_eq!(parse_retry_after(date, now), Some(Duration::from_secs(60)));
+        let past = "Sat, 05 Nov 1994 08:49:37 GMT";
+        assert_eq!(parse_retry_after(past, now), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(parse_retry_after("Sun, 06 Foo 1994 08:49:37 GMT", now), None);
+
+        let limited = mini_openai::Error::ApiError(format!(
+            "HTTP status client error (429 Too Many Requests){}30s",
+            RETRY_AFTER_NOTE
+        ));
+        assert!(is_rate_limited(&limited));
+        assert_eq!(retry_after(&limited), Some(Duration::from_secs(30)));
+        let plain = mini_openai::Error::ApiError("429 Too Many Requests".to_string());
+        assert_eq!(retry_after(&plain), None);
+        assert!(is_rate_limited(&plain));
+
+        // Only the status counts, not a 429 anywhere else in the text
+        let unavailable = mini_openai::Error::ApiError(format!(
+            "HTTP status server error (503 Service Unavailable) for url (/429){}429s",
+            RETRY_AFTER_NOTE
+        ));
+        assert!(!is_rate_limited(&unavailable));
+
+        let (delay, max) = (Duration::from_secs(5), Duration::from_secs(60));
+        assert_eq!(rate_limit_wait(&limited, 0, delay, max), Some(Duration::from_secs(30)));
+        assert_eq!(rate_limit_wait(&plain, 2, delay, max), Some(Duration::from_secs(20)));
+        assert_eq!(rate_limit_wait(&plain, 5, delay, max), Some(max));
+        let day =
+            mini_openai::Error::ApiError(format!("429 Too Many{}86400s", RETRY_AFTER_NOTE));
+        assert_eq!(rate_limit_wait(&day, 0, delay, max), None);
+    }
+}
@@ -0,0 +1,467 @@
+//! Cleaning up, checking and chunking reply text
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+};
+use crate::{config::*, connection::*};
+
+/// What the model wants to do with a structured turn
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ReplyAction {
+    /// Post the text to the channel
+    Say,
+    /// Post the text as an ACTION (/me)
+    Emote,
+    /// Stay silent
+    Ignore,
+}
+
+/// A reply in the format requested with --response-schema
+#[derive(Deserialize, Debug)]
+pub(crate) struct StructuredReply {
+    pub(crate) action: ReplyAction,
+    #[serde(default)]
+    pub(crate) text: String,
+}
+
+/// Finds the candidate a ranking model picked from its answer, which should be the
+/// candidate's number. Returns the index of the candidate
+pub(crate) fn parse_ranking(answer: &str, candidates: usize) -> Option<usize> {
+    let digits: String = answer
+        .trim_start()
+        .chars()
+        .skip_while(|c| !c.is_ascii_digit())
+        .take_while(char::is_ascii_digit)
+        .collect();
+    let number: usize = digits.parse().ok()?;
+    (1..=candidates).contains(&number).then(|| number - 1)
+}
+
+/// Puts every candidate in one reply, numbered so they can be told apart
+pub(crate) fn number_candidates(candidates: &[String]) -> String {
+    candidates
+        .iter()
+        .enumerate()
+        .map(|(index, candidate)| format!("[{}] {}", index + 1, candidate.trim()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub(crate) const STRUCTURED_REPLY_PROMPT: &str = "Respond only with a JSON object with the fields \"action\" and \"text\". Use action \"say\" to post text to the channel, \"emote\" to post it as an IRC action (/me), or \"ignore\" to stay silent.";
+
+/// The JSON schema for `StructuredReply`, in the shape of the `json_schema` response format
+pub(crate) fn structured_reply_schema() -> serde_json::Value {
+    serde_json::json!({
+        "name": "irc_turn",
+        "strict": true,
+        "schema": {
+            "type": "object",
+            "properties": {
+                "action": { "type": "string", "enum": ["say", "emote", "ignore"] },
+                "text": { "type": "string" }
+            },
+            "required": ["action", "text"],
+            "additionalProperties": false
+        }
+    })
+}
+
+/// Rough check for whether a message asks something: any line ending in `?` or
+/// starting with an interrogative word
+pub(crate) fn looks_like_question(msg: &str) -> bool {
+    const INTERROGATIVES: &[&str] = &[
+        "who", "what", "when", "where", "why", "how", "which", "whose", "is", "are", "can",
+        "could", "would", "should", "do", "does", "did", "will", "anyone", "any",
+    ];
+
+    msg.lines().map(str::trim).any(|line| {
+        let first_word = line
+            .split_whitespace()
+            .next()
+            .map(|w| w.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase());
+        line.ends_with('?') || first_word.is_some_and(|w| INTERROGATIVES.contains(&w.as_str()))
+    })
+}
+
+/// How faithfully model output is rendered in the channel
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ReplyFormat {
+    pub(crate) strip_newlines: bool,
+    pub(crate) strip_backticks: bool,
+    pub(crate) strip_markdown: bool,
+}
+
+/// Applies the configured post-processing to a model reply before it is sent
+pub(crate) fn clean_reply(reply: &str, format: ReplyFormat) -> String {
+    let mut cleaned = if format.strip_markdown {
+        markdown_to_irc(reply)
+    } else {
+        reply.to_string()
+    };
+
+    if format.strip_backticks {
+        cleaned = cleaned.replace('`', "");
+    }
+
+    if format.strip_newlines {
+        cleaned = cleaned.split_whitespace().collect::<Vec<_>>().join(" ");
+    }
+
+    cleaned
+}
+
+// IRC formatting control codes
+const IRC_BOLD: &str = "\x02";
+
+const IRC_ITALIC: &str = "\x1d";
+
+/// Converts common markdown to IRC formatting: bold and italics become control codes,
+/// headings, code fences and inline code markers are dropped and links become `text (url)`
+fn markdown_to_irc(text: &str) -> String {
+    text.lines()
+        .filter(|line| !line.trim_start().starts_with("```"))
+        .map(|line| {
+            let line = line.trim_start_matches('#').trim_start();
+            let line = replace_links(line);
+            let line = replace_delimited(&line, "**", IRC_BOLD);
+            let line = replace_delimited(&line, "__", IRC_BOLD);
+            let line = replace_delimited(&line, "*", IRC_ITALIC);
+            line.replace('`', "")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Replaces each balanced pair of delimiters with a control code, leaving an unmatched
+// trailing delimiter untouched
+fn replace_delimited(text: &str, delimiter: &str, control: &str) -> String {
+    let parts = text.split(delimiter).collect::<Vec<_>>();
+    let paired = (parts.len() - 1) / 2 * 2;
+    let mut out = String::with_capacity(text.len());
+
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            out.push_str(if i <= paired { control } else { delimiter });
+        }
+        out.push_str(part);
+    }
+
+    out
+}
+
+// Rewrites `[text](url)` links as `text (url)`
+fn replace_links(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+
+    while let Some(open) = rest.find('[') {
+        let Some((label, after)) = rest[open + 1..].split_once("](") else {
+            break;
+        };
+        let Some((url, after)) = after.split_once(')') else {
+            break;
+        };
+        out.push_str(&rest[..open]);
+        out.push_str(&format!("{} ({})", label, url));
+        rest = after;
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// Whether a chunk would show up as an empty line, counting bare formatting codes as empty
+pub(crate) fn is_blank(chunk: &str) -> bool {
+    chunk.chars().all(|c| c.is_whitespace() || c.is_control())
+}
+
+/// Replies kept per channel to recognize quotes of
+const QUOTABLE_REPLIES: usize = 10;
+
+/// Fewest words in a row a message has to share with a reply to quote it, so "yes" and
+/// "I think so" don't count
+const MIN_QUOTED_WORDS: usize = 3;
+
+/// Our latest replies per channel keyed by `channel_key`, as normalized words
+#[derive(Default)]
+pub(crate) struct RecentReplies(std::sync::Mutex<HashMap<String, VecDeque<Vec<String>>>>);
+
+impl RecentReplies {
+    pub(crate) fn record(&self, channel: &str, reply: &str) {
+        let mut channels = self.0.lock().unwrap();
+        let replies = channels.entry(channel_key(channel)).or_default();
+        replies.push_back(normalized_words(reply));
+        if replies.len() > QUOTABLE_REPLIES {
+            replies.pop_front();
+        }
+    }
+
+    /// Whether the message repeats enough of one of our replies in the channel, measured
+    /// against whichever of the two is shorter
+    pub(crate) fn quoted_in(&self, channel: &str, msg: &str, similarity: f64) -> bool {
+        let words = normalized_words(msg);
+        let channels = self.0.lock().unwrap();
+        let Some(replies) = channels.get(&channel_key(channel)) else {
+            return false;
+        };
+        replies.iter().any(|reply| {
+            let common = longest_common_run(reply, &words);
+            let shorter = reply.len().min(words.len());
+            common >= MIN_QUOTED_WORDS && common as f64 >= similarity * shorter as f64
+        })
+    }
+}
+
+/// Lowercased words without their punctuation, so quoting with different markup matches
+fn normalized_words(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase())
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+/// The length of the longest run of words both have in the same order
+fn longest_common_run(a: &[String], b: &[String]) -> usize {
+    let mut longest = 0;
+    let mut previous = vec![0; b.len() + 1];
+    for word in a {
+        let mut current = vec![0; b.len() + 1];
+        for (j, other) in b.iter().enumerate() {
+            if word == other {
+                current[j + 1] = previous[j] + 1;
+                longest = longest.max(current[j + 1]);
+            }
+        }
+        previous = current;
+    }
+    longest
+}
+
+/// The counter put in front of a numbered chunk, e.g. "(1/3) "
+pub(crate) fn chunk_counter(index: usize, total: usize) -> String {
+    format!("({}/{}) ", index, total)
+}
+
+/// Prefixes each chunk with its counter, once the total is known. A reply that fits in one
+/// chunk is left as it is
+pub(crate) fn number_reply_chunks(chunks: Vec<String>) -> Vec<String> {
+    if chunks.len() < 2 {
+        return chunks;
+    }
+    let total = chunks.len();
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| format!("{}{}", chunk_counter(index + 1, total), chunk))
+        .collect()
+}
+
+// Function to split a string into chunks of at most max_bytes bytes, preserving word boundaries
+pub(crate) fn split_into_chunks(text: &str, max_bytes: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current_chunk = String::new();
+
+    for word in text.split_whitespace() {
+        // If a single word is longer than max_bytes, split the word itself
+        if word.len() > max_bytes {
+            if !current_chunk.is_empty() {
+                chunks.push(current_chunk.clone());
+                current_chunk.clear();
+            }
+            chunks.extend(split_word_by_bytes(word, max_bytes));
+            continue;
+        }
+
+        if current_chunk.len() + word.len() + 1 > max_bytes && !current_chunk.is_empty() {
+            chunks.push(current_chunk.clone());
+            current_chunk.clear();
+        }
+
+        if !current_chunk.is_empty() {
+            current_chunk.push(' ');
+        }
+        current_chunk.push_str(word);
+    }
+
+    if !current_chunk.is_empty() {
+        chunks.push(current_chunk);
+    }
+
+    chunks
+}
+
+// Splits an oversized word on character boundaries so that no chunk exceeds max_bytes,
+// keeping multibyte characters intact
+fn split_word_by_bytes(word: &str, max_bytes: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current_chunk = String::new();
+
+    for c in word.chars() {
+        if current_chunk.len() + c.len_utf8() > max_bytes && !current_chunk.is_empty() {
+            chunks.push(std::mem::take(&mut current_chunk));
+        }
+        current_chunk.push(c);
+    }
+
+    if !current_chunk.is_empty() {
+        chunks.push(current_chunk);
+    }
+
+    chunks
+}
+
+/// Splits a reply into the chunks it's sent as: one or more per non-empty line, each
+/// fitting a PRIVMSG to `target` (or its CTCP wrapping when an emote) with `reserve`
+/// bytes to spare. The first line is prefixed with the nick being addressed, if any
+pub(crate) fn chunk_reply(
+    reply: &str,
+    address: Option<&str>,
+    target: &str,
+    max_line_bytes: usize,
+    emote: bool,
+    reserve: usize,
+) -> Vec<String> {
+    let mut address = address;
+    let mut reply_chunks = Vec::new();
+
+    for line in reply.split('\n') {
+        let trimmed_line = line.trim();
+        if trimmed_line.is_empty() {
+            continue; // Skip empty lines
+        }
+
+        // Address the sender on the first line only, before chunking so the prefix fits
+        let line = match address.take() {
+            Some(nick) => format!("{}: {}", nick, trimmed_line),
+            None => trimmed_line.to_string(),
+        };
+
+        // Further split each line into chunks if it exceeds the max IRC message size,
+        // leaving room for the CTCP wrapping of emotes
+        let mut chunk_bytes = privmsg_payload_limit(target, max_line_bytes);
+        if emote {
+            chunk_bytes = chunk_bytes.saturating_sub("\x01ACTION \x01".len());
+        }
+        // Blank lines look broken and still count towards flood limits
+        reply_chunks.extend(
+            split_into_chunks(&line, chunk_bytes.saturating_sub(reserve))
+                .into_iter()
+                .filter(|chunk| !is_blank(chunk)),
+        );
+    }
+    reply_chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markdown_is_converted_to_irc_formatting() {
+        let reply = "# Title\n```rust\nlet x = 1;\n```\n**bold** and *it* with `code` and [docs](https://x.y)";
+        assert_eq!(
+            markdown_to_irc(reply),
+            "Title\nlet x = 1;\n\x02bold\x02 and \x1dit\x1d with code and docs (https://x.y)"
+        );
+    }
+
+    #[test]
+    fn unmatched_delimiters_are_left_alone() {
+        assert_eq!(replace_delimited("2 * 3", "*", IRC_ITALIC), "2 * 3");
+        assert_eq!(replace_delimited("*a* * b", "*", IRC_ITALIC), "\x1da\x1d * b");
+    }
+
+    #[test]
+    fn detects_questions() {
+        assert!(looks_like_question("is this thing on"));
+        assert!(looks_like_question("hello\nHow do I build this?"));
+        assert!(looks_like_question("it broke?"));
+        assert!(!looks_like_question("thanks, that worked"));
+        assert!(!looks_like_question("however it failed"));
+    }
+
+    #[test]
+    fn formatting_only_chunks_are_blank() {
+        assert!(is_blank(""));
+        assert!(is_blank(" \t "));
+        assert!(is_blank("\x02\x02"));
+        assert!(!is_blank("\x02hi\x02"));
+    }
+
+    #[test]
+    fn parses_structured_replies() {
+        let reply: StructuredReply =
+            serde_json::from_str(r#"{"action": "emote", "text": "waves"}"#).unwrap();
+        assert_eq!((reply.action, reply.text.as_str()), (ReplyAction::Emote, "waves"));
+
+        let reply: StructuredReply = serde_json::from_str(r#"{"action": "ignore"}"#).unwrap();
+        assert_eq!(reply.action, ReplyAction::Ignore);
+
+        assert!(serde_json::from_str::<StructuredReply>(r#"{"action": "shout"}"#).is_err());
+    }
+
+    #[test]
+    fn splits_on_word_boundaries() {
+        let chunks = split_into_chunks("one two three four", 9);
+        assert_eq!(chunks, vec!["one two", "three", "four"]);
+    }
+
+    #[test]
+    fn oversized_emoji_word_never_exceeds_byte_limit() {
+        // 300 four-byte emoji with no whitespace, i.e. a single 1200 byte word
+        let word = "😀".repeat(300);
+        let limit = privmsg_payload_limit("#chat_0098", 512);
+        let chunks = split_into_chunks(&word, limit);
+
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|c| c.len() <= limit));
+        assert_eq!(chunks.concat(), word);
+    }
+
+    #[test]
+    fn oversized_word_is_split_on_char_boundaries() {
+        // 4-byte emoji joined into a ZWJ sequence, with a limit that doesn't divide evenly
+        let word = "👩‍👩‍👧‍👦".repeat(20);
+        let chunks = split_into_chunks(&word, 10);
+
+        assert!(chunks.iter().all(|c| c.len() <= 10));
+        assert_eq!(chunks.concat(), word);
+    }
+
+    #[test]
+    fn reads_the_ranking_models_pick() {
+        assert_eq!(parse_ranking("2", 3), Some(1));
+        assert_eq!(parse_ranking("Reply 3 is best.", 3), Some(2));
+        assert_eq!(parse_ranking("4", 3), None);
+        assert_eq!(parse_ranking("0", 3), None);
+        assert_eq!(parse_ranking("none of them", 3), None);
+    }
+
+    #[test]
+    fn numbers_multi_chunk_replies() {
+        let chunks = vec!["one".to_string(), "two".to_string(), "three".to_string()];
+        assert_eq!(number_reply_chunks(chunks), ["(1/3) one", "(2/3) two", "(3/3) three"]);
+        assert_eq!(number_reply_chunks(vec!["only".to_string()]), ["only"]);
+    }
+
+    #[test]
+    fn oversized_word_flushes_pending_chunk_first() {
+        let text = format!("hi {} bye", "🎉".repeat(5));
+        let chunks = split_into_chunks(&text, 8);
+
+        assert_eq!(chunks, vec!["hi", "🎉🎉", "🎉🎉", "🎉", "bye"]);
+    }
+
+    #[test]
+    fn chunks_address_the_sender_once() {
+        let reply = "first line\n\n  second line";
+        let chunks = chunk_reply(reply, Some("alice"), "#rust", 512, false, 0);
+        assert_eq!(chunks, ["alice: first line", "second line"]);
+
+        let limit = privmsg_payload_limit("#rust", 40);
+        let chunks = chunk_reply(&"word ".repeat(20), None, "#rust", 40, false, 6);
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.len() + 6 <= limit));
+    }
+}
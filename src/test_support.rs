@@ -0,0 +1,245 @@
+//! Helpers shared by the tests of several modules
+
+use futures::future;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    fs::File,
+    path::PathBuf,
+    sync::Arc,
+};
+use tokio::sync::Mutex;
+use tokio::time::{self, Duration, Instant};
+use crate::{
+    config::*, connection::*, control::*, history::*, processor::*, provider::*, reply::*, turns::*,
+};
+
+/// Answers every request with the same reply and keeps each request's (role, content)
+/// pairs for inspection
+#[derive(Default)]
+pub(crate) struct MockBackend {
+    pub(crate) reply: String,
+    pub(crate) requests: std::sync::Mutex<Vec<Vec<(String, String)>>>,
+    /// Requests starting with this message are answered only after this long
+    pub(crate) slow: Option<(String, Duration)>,
+}
+
+impl ChatBackend for Arc<MockBackend> {
+    fn chat_completions<'a>(
+        &'a self,
+        request: &'a mini_openai::ChatCompletions,
+    ) -> future::BoxFuture<'a, Result<mini_openai::ChatCompletionsResponse, mini_openai::Error>>
+    {
+        let messages = request.messages.iter();
+        let seen = messages.map(|m| (m.role.clone(), m.content.clone())).collect();
+        self.requests.lock().unwrap().push(seen);
+        let response = mini_openai::ChatCompletionsResponse {
+            id: "mock".to_string(),
+            object: "chat.completion".to_string(),
+            created: 0,
+            model: request.model.clone(),
+            choices: vec![mini_openai::Choice {
+                index: 0,
+                message: mini_openai::Message {
+                    content: self.reply.clone(),
+                    role: mini_openai::ROLE_ASSISTANT.to_string(),
+                },
+                finish_reason: "stop".to_string(),
+            }],
+        };
+        let delay = self.slow.as_ref().and_then(|(first, delay)| {
+            let first_message = request.messages.first().map(|m| m.content.as_str());
+            (first_message == Some(first.as_str())).then_some(*delay)
+        });
+        Box::pin(async move {
+            if let Some(delay) = delay {
+                time::sleep(delay).await;
+            }
+            Ok(response)
+        })
+    }
+}
+
+/// A leader named "bot" in #rust with every option at its default, printing its replies
+pub(crate) fn test_processor(llm: Arc<MockBackend>) -> Processor {
+    let channels = [ChannelConfig::parse("#rust").unwrap()];
+    Processor {
+        llm: Arc::new(llm),
+        http: reqwest::Client::new(),
+        model: "mock".to_string(),
+        nickname: "bot".to_string(),
+        leader: true,
+        history: History::new(&channels),
+        rng: Arc::new(Mutex::new(ChaCha8Rng::seed_from_u64(0))),
+        seeded: false,
+        stop: Vec::new(),
+        transcript: None,
+        model_weights: None,
+        channel_models: HashMap::new(),
+        switched_model: SwitchedModel::default(),
+        system_prompt: None,
+        examples: Vec::new(),
+        personas: HashMap::new(),
+        introduction: None,
+        response_schema: false,
+        departed_peers: DepartedPeers::default(),
+        peers: vec!["peerbot".to_string()],
+        human_floor: None,
+        human_floors: std::sync::Mutex::default(),
+        referee: false,
+        referee_nick: None,
+        referee_turns: std::sync::Mutex::default(),
+        dedup_window: None,
+        recent_lines: RecentLines::default(),
+        message_rate: MessageRate::default(),
+        greeted: Arc::default(),
+        output: Output::Stdout("bot".to_string()),
+        participants: Participants::default(),
+        started: Instant::now(),
+        context: ContextSettings {
+            include_participants: false,
+            context_stats: false,
+            merge_same_role: false,
+            turn_separator: "\n".to_string(),
+            decay_after: None,
+            decay_factor: 2.0,
+        },
+        triggers: TriggerSettings {
+            questions_only: false,
+            min_length: None,
+            suppress_if_contains: Vec::new(),
+            mention_strict: false,
+        },
+        choices: ChoiceSettings {
+            n: 1,
+            strategy: ChoiceStrategy::First,
+            ranking_model: None,
+        },
+        reply: ReplySettings {
+            format: ReplyFormat::default(),
+            max_line_bytes: 512,
+            address_sender: false,
+            strip_own_name: false,
+            number_chunks: false,
+            hidden_delimiter: None,
+            prefix: None,
+        },
+        quotes: QuoteSettings {
+            on_quote: None,
+            similarity: 0.8,
+        },
+        thinking: ThinkingSettings {
+            message: None,
+            after: Duration::ZERO,
+            interval: Duration::ZERO,
+        },
+        send: SendSettings {
+            retries: 0,
+            retry_delay: Duration::ZERO,
+            resend_failed: false,
+            paste_url: None,
+            paste_threshold: 4,
+        },
+        rate_limit: RateLimitSettings {
+            retries: 0,
+            delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+        },
+        refusal: RefusalSettings {
+            phrases: Vec::new(),
+            model: None,
+            on_refusal: OnRefusal::Skip,
+            reframe: DEFAULT_REFUSAL_REFRAME.to_string(),
+            substitute: DEFAULT_REFUSAL_SUBSTITUTE.to_string(),
+        },
+        summary: SummarySettings {
+            on_exit: false,
+            prompt: DEFAULT_SUMMARY_PROMPT.to_string(),
+            file: None,
+            rolling: None,
+            rolling_window: 20,
+            on_backlog: SummaryBacklog::Skip,
+        },
+        respond_channels: None,
+        fallback_reply: String::new(),
+        last_thinking: std::sync::Mutex::default(),
+        generations: Generations::default(),
+        history_saves: None,
+        send_failed: Arc::default(),
+        send_queues: SendQueues::default(),
+        unsent: Unsent::default(),
+        presence: Arc::new(Mutex::new(Presence {
+            last_reply: Instant::now(),
+            away: false,
+        })),
+        on_reply_command: None,
+        breaker: std::sync::Mutex::new(CircuitBreaker::new(5, Duration::ZERO)),
+        recent_replies: RecentReplies::default(),
+        startup_grace: None,
+        joined_at: JoinedAt::default(),
+        rolling_summaries: RollingSummaries::default(),
+        paused: Arc::default(),
+        muted: Muted::default(),
+        reply_routes: Vec::new(),
+        stats: Arc::default(),
+        turn_order: TurnOrder::Channel,
+        turn_queues: std::sync::Mutex::default(),
+    }
+}
+
+/// A file in the temp directory for a test to write to, removed when dropped so a failed
+/// assert doesn't leave it behind
+pub(crate) struct TempFile {
+    pub(crate) path: PathBuf,
+}
+
+impl TempFile {
+    /// Unique to the test run, as long as `name` is unique among the tests
+    pub(crate) fn new(name: &str) -> Self {
+        let file = format!("chatroom-{}-{}", std::process::id(), name);
+        TempFile {
+            path: env::temp_dir().join(file),
+        }
+    }
+
+    /// Output for the nick "bot" written to the file, which is created afresh
+    pub(crate) fn output(&self) -> Output {
+        let sink = File::create(&self.path).unwrap();
+        Output::File("bot".to_string(), Arc::new(std::sync::Mutex::new(sink)))
+    }
+
+    /// What has been written so far
+    pub(crate) fn read(&self) -> String {
+        std::fs::read_to_string(&self.path).unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Moves paused time on a few milliseconds at a time, letting woken tasks run in between
+pub(crate) async fn advance_in_steps(by: Duration) {
+    let step = Duration::from_millis(10);
+    let mut left = by;
+    while !left.is_zero() {
+        time::advance(step.min(left)).await;
+        tokio::task::yield_now().await;
+        left = left.saturating_sub(step);
+    }
+}
+
+pub(crate) fn turn(messages: &[(&str, &str)]) -> Turn {
+    Turn {
+        channel: "#rust".to_string(),
+        messages: messages.iter().map(|(s, m)| (s.to_string(), m.to_string())).collect(),
+        addressed_by: HashSet::new(),
+        generation: 0,
+        revive: false,
+        received: Instant::now(),
+    }
+}
@@ -0,0 +1,723 @@
+//! Buffering incoming channel messages into turns
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
+use tokio::sync::{Mutex, Notify, mpsc};
+use tokio::time::{Duration, Instant};
+use tracing::{error, warn};
+use crate::config::*;
+
+/// A nick folded by the rfc1459 casemapping most servers use, under which "Alice[m]" and
+/// "alice{m}" are the same nick
+pub(crate) fn fold_nick(nick: &str) -> String {
+    nick.chars()
+        .map(|c| match c {
+            '[' => '{',
+            ']' => '}',
+            '\\' => '|',
+            '~' => '^',
+            c => c.to_ascii_lowercase(),
+        })
+        .collect()
+}
+
+/// Whether two nicks are the same to the server
+pub(crate) fn same_nick(a: &str, b: &str) -> bool {
+    fold_nick(a) == fold_nick(b)
+}
+
+/// Whether a message mentions the given nickname. When strict, the nickname has to stand
+/// as a word of its own, so "robot" doesn't mention "bot" but "bot:" and "@bot" do
+pub(crate) fn mentions(msg: &str, nickname: &str, strict: bool) -> bool {
+    // Folding keeps byte offsets the same in both strings
+    let msg = fold_nick(msg);
+    let nickname = fold_nick(nickname);
+    if !strict || nickname.is_empty() {
+        return msg.contains(&nickname);
+    }
+
+    msg.match_indices(&nickname).any(|(start, _)| {
+        let before = msg[..start].chars().next_back();
+        let after = msg[start + nickname.len()..].chars().next();
+        !before.is_some_and(is_nick_char) && !after.is_some_and(is_nick_char)
+    })
+}
+
+/// Whether the character can be part of an IRC nickname
+fn is_nick_char(c: char) -> bool {
+    c.is_alphanumeric() || "-_[]\\`^{}|".contains(c)
+}
+
+/// How a channel message reads as conversation: ACTION emotes (/me) become `* nick waves`,
+/// and other CTCP requests such as VERSION aren't conversation at all, giving None
+pub(crate) fn conversation_text(msg: &str, sender: &str) -> Option<String> {
+    let Some(ctcp) = msg.strip_prefix('\x01') else {
+        return Some(msg.to_string());
+    };
+    let ctcp = ctcp.strip_suffix('\x01').unwrap_or(ctcp);
+    let action = ctcp.strip_prefix("ACTION ")?;
+    Some(format!("* {} {}", sender, action))
+}
+
+/// The rest of a message that starts by addressing the given nickname, as in "bot: hi" or
+/// "bot, hi"
+pub(crate) fn strip_nick_prefix<'a>(msg: &'a str, nickname: &str) -> Option<&'a str> {
+    let prefix = msg.get(..nickname.len())?;
+    if !same_nick(prefix, nickname) {
+        return None;
+    }
+    let rest = msg[nickname.len()..].strip_prefix([':', ','])?;
+    Some(rest.trim_start())
+}
+
+/// The message as it should be buffered, and whether it addressed us with a nick prefix that
+/// --strip-nick-prefix removed
+pub(crate) fn addressed_message<'a>(args: &Args, msg: &'a str) -> (&'a str, bool) {
+    match strip_nick_prefix(msg, &args.nickname) {
+        Some(rest) if args.strip_nick_prefix && !rest.is_empty() => (rest, true),
+        _ => (msg, false),
+    }
+}
+
+/// Lines said recently in each channel, keyed by `channel_key` and the exact line, with when
+#[derive(Default)]
+pub(crate) struct RecentLines(std::sync::Mutex<HashMap<(String, String), Instant>>);
+
+impl RecentLines {
+    /// Notes that a line was said, returning whether it had already been said in the channel
+    /// within the window. Older lines are forgotten
+    pub(crate) fn repeated(
+        &self,
+        channel: &str,
+        line: &str,
+        window: Duration,
+        now: Instant,
+    ) -> bool {
+        let mut lines = self.0.lock().unwrap();
+        lines.retain(|_, said| now.duration_since(*said) < window);
+        lines.insert((channel_key(channel), line.to_string()), now).is_some()
+    }
+}
+
+/// How far back --context-stats looks to work out a channel's message rate
+const MESSAGE_RATE_WINDOW: Duration = Duration::from_secs(600);
+
+/// When messages came in lately, per channel keyed by `channel_key`
+#[derive(Default)]
+pub(crate) struct MessageRate(std::sync::Mutex<HashMap<String, VecDeque<Instant>>>);
+
+impl MessageRate {
+    pub(crate) fn record(&self, channel: &str, count: usize, at: Instant) {
+        let mut channels = self.0.lock().unwrap();
+        let times = channels.entry(channel_key(channel)).or_default();
+        times.extend(std::iter::repeat_n(at, count));
+    }
+
+    /// Messages a minute over the window, or over the time since `started` if that's shorter
+    pub(crate) fn per_minute(&self, channel: &str, started: Instant, now: Instant) -> f64 {
+        let mut channels = self.0.lock().unwrap();
+        let Some(times) = channels.get_mut(&channel_key(channel)) else {
+            return 0.0;
+        };
+        while times.front().is_some_and(|at| now.duration_since(*at) > MESSAGE_RATE_WINDOW) {
+            times.pop_front();
+        }
+        let span = MESSAGE_RATE_WINDOW.min(now.duration_since(started));
+        times.len() as f64 / span.as_secs_f64().max(60.0) * 60.0
+    }
+}
+
+/// An ordered group of (sender, message) pairs from one channel, answered with a single reply
+pub(crate) struct Turn {
+    pub(crate) channel: String,
+    pub(crate) messages: Vec<(String, String)>,
+    /// Lowercased senders whose nick prefix addressing us was stripped from their messages
+    pub(crate) addressed_by: HashSet<String>,
+    /// The channel's generation when the turn was queued
+    pub(crate) generation: u64,
+    /// Started by us to revive a quiet channel, so it has no messages and answers nobody
+    pub(crate) revive: bool,
+    /// When the turn's first message arrived
+    pub(crate) received: Instant,
+}
+
+/// When each channel last heard from someone, keyed by `channel_key`
+#[derive(Clone, Default)]
+pub(crate) struct Idleness(Arc<std::sync::Mutex<HashMap<String, ChannelIdleness>>>);
+
+struct ChannelIdleness {
+    channel: String,
+    last_heard: Instant,
+    /// Whether we've tried reviving the channel since it was last heard from
+    revived: bool,
+}
+
+impl Idleness {
+    pub(crate) fn heard(&self, channel: &str) {
+        self.0.lock().unwrap().insert(
+            channel_key(channel),
+            ChannelIdleness {
+                channel: channel.to_string(),
+                last_heard: Instant::now(),
+                revived: false,
+            },
+        );
+    }
+
+    /// Channels that have been quiet for `idle_after` and haven't been revived yet, which
+    /// are marked revived. Channels never heard from have nothing to revive
+    pub(crate) fn due(&self, idle_after: Duration, now: Instant) -> Vec<String> {
+        let mut due = Vec::new();
+        for idleness in self.0.lock().unwrap().values_mut() {
+            if !idleness.revived && now.duration_since(idleness.last_heard) >= idle_after {
+                idleness.revived = true;
+                due.push(idleness.channel.clone());
+            }
+        }
+        due
+    }
+}
+
+/// How many times each channel has been reset, keyed by `channel_key`. Turns are tagged with
+/// their channel's generation when queued, so the processor can drop the ones that were
+/// still waiting when a `!reset` went through instead of rebuilding the old context
+#[derive(Clone, Default)]
+pub(crate) struct Generations(Arc<std::sync::Mutex<HashMap<String, u64>>>);
+
+impl Generations {
+    pub(crate) fn current(&self, channel: &str) -> u64 {
+        self.0.lock().unwrap().get(&channel_key(channel)).copied().unwrap_or_default()
+    }
+
+    pub(crate) fn advance(&self, channel: &str) {
+        *self.0.lock().unwrap().entry(channel_key(channel)).or_default() += 1;
+    }
+}
+
+impl Turn {
+    /// Whether the sender, or anyone when None, mentioned us in this turn
+    pub(crate) fn mentions(&self, sender: Option<&str>, nickname: &str, strict: bool) -> bool {
+        let from_sender = |from: &str| sender.is_none_or(|s| same_nick(s, from));
+        self.addressed_by.iter().any(|from| from_sender(from))
+            || self
+                .messages
+                .iter()
+                .any(|(from, msg)| from_sender(from) && mentions(msg, nickname, strict))
+    }
+}
+
+/// Incoming messages waiting to be flushed to the processor as turns
+pub(crate) struct PendingMessages {
+    /// Fixed batching window, or None to flush once the sender or channel goes idle
+    batch_window: Option<Duration>,
+    buffer_by: BufferBy,
+    /// Key: (channel, sender nickname folded by `fold_nick`)
+    by_sender: HashMap<(String, String), SenderMessages>,
+    /// Key: channel
+    batches: HashMap<String, ChannelBatch>,
+    /// Key: channel, Value: folded senders of pending messages that addressed us by a
+    /// stripped nick prefix
+    addressed: HashMap<String, HashSet<String>>,
+    /// Generations to tag flushed turns with
+    generations: Generations,
+    /// Woken on every new message, and when input closes, so the buffer task can sleep
+    /// until something is due
+    pub(crate) arrivals: Arc<Notify>,
+    /// Set once no more input will arrive, so the buffer task can drain and shut the
+    /// pipeline down
+    pub(crate) closed: bool,
+    /// Cap on the characters in each flushed turn
+    pub(crate) max_combined_chars: Option<usize>,
+}
+
+/// What a sender has said since their last turn was flushed
+struct SenderMessages {
+    /// The nick as the sender first wrote it
+    sender: String,
+    messages: Vec<String>,
+    first_received: Instant,
+    last_received: Instant,
+    /// Whether the last message looked cut off by the sender's client
+    cut_off: bool,
+}
+
+/// What a channel has said since its last turn was flushed
+struct ChannelBatch {
+    /// (sender, message) in arrival order
+    messages: Vec<(String, String)>,
+    /// When the current window opened or, when buffering by channel, the last received time
+    since: Instant,
+    first_received: Instant,
+}
+
+impl PendingMessages {
+    pub(crate) fn new(
+        batch_window: Option<Duration>,
+        buffer_by: BufferBy,
+        generations: Generations,
+    ) -> Self {
+        Self {
+            batch_window,
+            buffer_by,
+            by_sender: HashMap::new(),
+            batches: HashMap::new(),
+            addressed: HashMap::new(),
+            generations,
+            arrivals: Arc::new(Notify::new()),
+            closed: false,
+            max_combined_chars: None,
+        }
+    }
+
+    pub(crate) fn close(&mut self) {
+        self.closed = true;
+        self.arrivals.notify_one();
+    }
+
+    /// When the next sender or channel will be ready to flush, if anything is pending
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        if self.batch_window.is_some() || self.buffer_by == BufferBy::Channel {
+            let wait = self.batch_window.unwrap_or(Duration::from_secs(1));
+            return self.batches.values().map(|batch| batch.since + wait).min();
+        }
+        let idle = Duration::from_secs(1);
+        self.by_sender.values().map(|pending| pending.last_received + idle).min()
+    }
+
+    /// Drops everything pending for a channel
+    pub(crate) fn discard(&mut self, channel: &str) {
+        self.by_sender.retain(|(pending, _), _| !pending.eq_ignore_ascii_case(channel));
+        self.batches.retain(|pending, _| !pending.eq_ignore_ascii_case(channel));
+        self.addressed.retain(|pending, _| !pending.eq_ignore_ascii_case(channel));
+    }
+
+    fn push(&mut self, channel: &str, sender: &str, msg: &str, addressed: bool) {
+        let now = Instant::now();
+        self.arrivals.notify_one();
+
+        if addressed {
+            let senders = self.addressed.entry(channel.to_string()).or_default();
+            senders.insert(fold_nick(sender));
+        }
+
+        if self.batch_window.is_some() || self.buffer_by == BufferBy::Channel {
+            let batch = self
+                .batches
+                .entry(channel.to_string())
+                .or_insert(ChannelBatch {
+                    messages: Vec::new(),
+                    since: now,
+                    first_received: now,
+                });
+            batch.messages.push((sender.to_string(), msg.to_string()));
+            if self.batch_window.is_none() {
+                batch.since = now; // Wait for the whole channel to go quiet
+            }
+            return;
+        }
+
+        let entry = self
+            .by_sender
+            .entry((channel.to_string(), fold_nick(sender)))
+            .or_insert(SenderMessages {
+                sender: sender.to_string(),
+                messages: Vec::new(),
+                first_received: now,
+                last_received: now,
+                cut_off: false,
+            });
+        // Put a long message the sender's client split over several lines back together
+        let continues = entry.cut_off && now.duration_since(entry.last_received) <= WIRE_SPLIT_GAP;
+        match entry.messages.last_mut() {
+            Some(last) if continues => last.push_str(msg),
+            _ => entry.messages.push(msg.to_string()),
+        }
+        entry.last_received = now;
+        entry.cut_off = looks_cut_off(msg);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.by_sender.is_empty() && self.batches.is_empty()
+    }
+
+    /// Takes every turn that is ready to be processed
+    pub(crate) fn flush_ready(&mut self, now: Instant) -> Vec<Turn> {
+        let mut to_process = Vec::new();
+
+        if self.batch_window.is_some() || self.buffer_by == BufferBy::Channel {
+            // Flush each channel whose window has been open long enough, or that went quiet
+            let wait = self.batch_window.unwrap_or(Duration::from_secs(1));
+            let addressed = &mut self.addressed;
+            let generations = &self.generations;
+            self.batches.retain(|channel, batch| {
+                if now.duration_since(batch.since) < wait {
+                    return true;
+                }
+                to_process.push(Turn {
+                    channel: channel.clone(),
+                    messages: std::mem::take(&mut batch.messages),
+                    addressed_by: addressed.remove(channel).unwrap_or_default(),
+                    generation: generations.current(channel),
+                    revive: false,
+                    received: batch.first_received,
+                });
+                false
+            });
+            self.cap_combined(&mut to_process);
+            return to_process;
+        }
+
+        // Iterate over the buffer and collect senders whose last message was over 1 second ago
+        let mut flushed = Vec::new();
+        for ((channel, folded), pending) in self.by_sender.iter_mut() {
+            if now.duration_since(pending.last_received) >= Duration::from_secs(1) {
+                // Combine messages into one
+                let combined_msg = pending.messages.join("\n");
+                let addressed = self
+                    .addressed
+                    .get_mut(channel)
+                    .is_some_and(|senders| senders.remove(folded));
+                let turn = Turn {
+                    channel: channel.clone(),
+                    messages: vec![(pending.sender.clone(), combined_msg)],
+                    addressed_by: addressed.then(|| folded.clone()).into_iter().collect(),
+                    generation: self.generations.current(channel),
+                    revive: false,
+                    received: pending.first_received,
+                };
+                flushed.push((pending.first_received, turn));
+                // Clear the buffer for this sender
+                pending.messages = Vec::new();
+            }
+        }
+
+        // Remove senders with empty message buffers
+        self.by_sender.retain(|_, pending| !pending.messages.is_empty());
+        self.addressed.retain(|_, senders| !senders.is_empty());
+
+        // The map has no order, so put the turns back in the order their senders spoke
+        flushed.sort_by_key(|(first_received, _)| *first_received);
+        to_process.extend(flushed.into_iter().map(|(_, turn)| turn));
+        self.cap_combined(&mut to_process);
+        to_process
+    }
+
+    fn cap_combined(&self, turns: &mut [Turn]) {
+        let Some(max_chars) = self.max_combined_chars else {
+            return;
+        };
+        for turn in turns {
+            if cap_messages(&mut turn.messages, max_chars) {
+                warn!("Cut a turn in {} down to {} characters", turn.channel, max_chars);
+            }
+        }
+    }
+}
+
+/// Appended to a message cut short by --max-combined-chars
+const COMBINED_CUT_MARKER: &str = " [cut]";
+
+/// Cuts (sender, message) pairs down to `max_chars` in all, marking the message that goes
+/// over and dropping the rest. Returns whether anything was cut
+fn cap_messages(messages: &mut Vec<(String, String)>, max_chars: usize) -> bool {
+    let mut left = max_chars;
+    for index in 0..messages.len() {
+        let msg = &mut messages[index].1;
+        let chars = msg.chars().count();
+        if chars <= left {
+            left -= chars;
+            continue;
+        }
+        let cut = msg.char_indices().nth(left).map_or(msg.len(), |(i, _)| i);
+        msg.truncate(cut);
+        msg.push_str(COMBINED_CUT_MARKER);
+        messages.truncate(index + 1);
+        return true;
+    }
+    false
+}
+
+pub(crate) type MessageBuffer = Arc<Mutex<PendingMessages>>;
+
+/// Longest gap between the parts of a message split by the sender's client
+const WIRE_SPLIT_GAP: Duration = Duration::from_millis(300);
+
+/// Shortest line that could have been split for hitting the IRC line limit
+const WIRE_SPLIT_MIN_BYTES: usize = 350;
+
+/// Whether a line looks like part of a long message that the sender's client split over
+/// several lines, filling most of an IRC line without ending like a sentence. It's only
+/// joined with the next line if that follows within `WIRE_SPLIT_GAP`
+fn looks_cut_off(line: &str) -> bool {
+    line.len() >= WIRE_SPLIT_MIN_BYTES && !line.trim_end().ends_with(['.', '!', '?', ':', ';', ')'])
+}
+
+/// Where incoming channel messages go on their way to the processor
+#[derive(Clone)]
+pub(crate) enum Inbox {
+    /// Collected in the buffer, which the buffer task flushes as turns
+    Buffered(MessageBuffer),
+    /// Sent to the processor right away as a turn of their own, with --no-buffer
+    Direct(mpsc::Sender<Turn>, Generations),
+}
+
+/// Adds a message to the buffer with the current timestamp, or hands it straight to the
+/// processor when not buffering
+pub(crate) async fn buffer_message(
+    inbox: &Inbox,
+    channel: &str,
+    sender: &str,
+    msg: &str,
+    addressed: bool,
+) {
+    match inbox {
+        Inbox::Buffered(buffer) => buffer.lock().await.push(channel, sender, msg, addressed),
+        Inbox::Direct(turns, generations) => {
+            let turn = Turn {
+                channel: channel.to_string(),
+                messages: vec![(sender.to_string(), msg.to_string())],
+                addressed_by: addressed.then(|| fold_nick(sender)).into_iter().collect(),
+                generation: generations.current(channel),
+                revive: false,
+                received: Instant::now(),
+            };
+            if let Err(e) = turns.send(turn).await {
+                error!("Failed to send message to processor: {}", e);
+            }
+        }
+    }
+}
+
+/// A step incoming channel messages go through before they're buffered, to rewrite them
+/// (redact, translate, intercept) or drop them by returning None. Filters run in the order
+/// they were added, each getting what the one before returned
+pub trait MessageFilter: Send + Sync {
+    fn process(&self, sender: &str, msg: &str) -> Option<String>;
+}
+
+impl<F> MessageFilter for F
+where
+    F: Fn(&str, &str) -> Option<String> + Send + Sync,
+{
+    fn process(&self, sender: &str, msg: &str) -> Option<String> {
+        self(sender, msg)
+    }
+}
+
+/// Filters added to a bot, in order
+pub(crate) type MessageFilters = Arc<Vec<Box<dyn MessageFilter>>>;
+
+/// Runs a message through every filter, None as soon as one drops it
+pub(crate) fn filter_message(
+    filters: &[Box<dyn MessageFilter>],
+    sender: &str,
+    msg: &str,
+) -> Option<String> {
+    filters.iter().try_fold(msg.to_string(), |msg, filter| filter.process(sender, &msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{control::is_admin, history::history_role};
+    use crate::test_support::*;
+
+    #[test]
+    fn caps_the_characters_in_a_turn() {
+        let mut pending = PendingMessages::new(None, BufferBy::Sender, Generations::default());
+        pending.max_combined_chars = Some(11);
+        for line in ["spam", "spam", "spam", "spam"] {
+            pending.push("#rust", "alice", line, false);
+        }
+        let turns = pending.flush_ready(Instant::now() + Duration::from_secs(1));
+        assert_eq!(turns[0].messages[0].1, "spam\nspam\ns [cut]");
+
+        let mut messages = vec![
+            ("alice".to_string(), "héllo".to_string()),
+            ("bob".to_string(), "wörld".to_string()),
+            ("carol".to_string(), "!".to_string()),
+        ];
+        assert!(!cap_messages(&mut messages.clone(), 11));
+        assert!(cap_messages(&mut messages, 7));
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].1, "wö [cut]");
+    }
+
+    #[test]
+    fn channel_buffer_flushes_once_the_channel_is_quiet() {
+        let mut pending = PendingMessages::new(None, BufferBy::Channel, Generations::default());
+        pending.push("#rust", "alice", "hi", false);
+        pending.push("#rust", "bob", "hello", false);
+        assert!(pending.flush_ready(Instant::now()).is_empty());
+
+        let turns = pending.flush_ready(Instant::now() + Duration::from_secs(1));
+        assert_eq!(turns.len(), 1);
+        assert_eq!(
+            turns[0].messages,
+            vec![
+                ("alice".to_string(), "hi".to_string()),
+                ("bob".to_string(), "hello".to_string())
+            ]
+        );
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn strips_nick_prefixes_addressing_us() {
+        assert_eq!(strip_nick_prefix("bot: hi there", "bot"), Some("hi there"));
+        assert_eq!(strip_nick_prefix("Bot, hi", "bot"), Some("hi"));
+        assert_eq!(strip_nick_prefix("bots: hi", "bot"), None);
+        assert_eq!(strip_nick_prefix("hi bot: x", "bot"), None);
+        assert_eq!(strip_nick_prefix("bö", "bot"), None);
+    }
+
+    #[test]
+    fn next_deadline_is_the_earliest_pending_flush() {
+        let mut pending = PendingMessages::new(None, BufferBy::Sender, Generations::default());
+        assert_eq!(pending.next_deadline(), None);
+
+        pending.push("#rust", "alice", "hi", false);
+        let alice_due = pending.by_sender[&("#rust".to_string(), "alice".to_string())]
+            .last_received
+            + Duration::from_secs(1);
+        pending.push("#rust", "bob", "hello", false);
+        assert_eq!(pending.next_deadline(), Some(alice_due));
+
+        pending.flush_ready(alice_due + Duration::from_secs(1));
+        assert_eq!(pending.next_deadline(), None);
+    }
+
+    #[test]
+    fn rejoins_lines_split_on_the_wire() {
+        let long = "word ".repeat(80);
+        let mut pending = PendingMessages::new(None, BufferBy::Sender, Generations::default());
+        pending.push("#rust", "alice", &long, false);
+        pending.push("#rust", "alice", "rest", false);
+        pending.push("#rust", "alice", "new thought", false);
+
+        let turns = pending.flush_ready(Instant::now() + Duration::from_secs(1));
+        assert_eq!(turns[0].messages[0].1, format!("{}rest\nnew thought", long));
+
+        assert!(looks_cut_off(&long));
+        assert!(!looks_cut_off(&format!("{}end.", long)));
+        assert!(!looks_cut_off("short"));
+    }
+
+    #[test]
+    fn senders_flushed_together_keep_their_order() {
+        let mut pending = PendingMessages::new(None, BufferBy::Sender, Generations::default());
+        for sender in ["carol", "alice", "dave", "bob"] {
+            pending.push("#rust", sender, "hi", false);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let turns = pending.flush_ready(Instant::now() + Duration::from_secs(1));
+        let senders: Vec<&str> = turns.iter().map(|turn| turn.messages[0].0.as_str()).collect();
+        assert_eq!(senders, ["carol", "alice", "dave", "bob"]);
+    }
+
+    #[test]
+    fn strict_mentions_need_word_boundaries() {
+        for msg in ["bot: hi", "hey bot, you there?", "@Bot ping", "thanks BOT"] {
+            assert!(mentions(msg, "bot", true), "{}", msg);
+        }
+        for msg in ["robot uprising", "bots everywhere", "bot_2 said so"] {
+            assert!(!mentions(msg, "bot", true), "{}", msg);
+        }
+        assert!(mentions("robot uprising", "bot", false));
+    }
+
+    #[test]
+    fn quiet_channels_are_revived_once_per_quiet_spell() {
+        let idleness = Idleness::default();
+        let idle_after = Duration::from_secs(60);
+        idleness.heard("#Rust");
+        assert!(idleness.due(idle_after, Instant::now()).is_empty());
+
+        let later = Instant::now() + idle_after;
+        assert_eq!(idleness.due(idle_after, later), ["#Rust"]);
+        assert!(idleness.due(idle_after, later + idle_after).is_empty());
+
+        idleness.heard("#rust");
+        assert_eq!(idleness.due(idle_after, Instant::now() + idle_after), ["#rust"]);
+    }
+
+    #[test]
+    fn turns_carry_the_generation_they_were_queued_in() {
+        let generations = Generations::default();
+        let mut pending = PendingMessages::new(None, BufferBy::Sender, generations.clone());
+        pending.push("#rust", "alice", "old", false);
+        pending.discard("#Rust");
+        assert!(pending.is_empty());
+
+        generations.advance("#RUST");
+        pending.push("#rust", "alice", "new", false);
+        let turns = pending.flush_ready(Instant::now() + Duration::from_secs(1));
+        assert_eq!(turns[0].generation, 1);
+        assert_eq!(generations.current("#other"), 0);
+    }
+
+    #[test]
+    fn emotes_read_as_conversation() {
+        assert_eq!(conversation_text("hi", "alice").as_deref(), Some("hi"));
+        assert_eq!(
+            conversation_text("\x01ACTION waves\x01", "alice").as_deref(),
+            Some("* alice waves")
+        );
+        assert_eq!(conversation_text("\x01VERSION\x01", "alice"), None);
+    }
+
+    #[test]
+    fn forgets_lines_outside_the_window() {
+        let recent = RecentLines::default();
+        let window = Duration::from_secs(5);
+        let now = Instant::now();
+        assert!(!recent.repeated("#rust", "hi", window, now));
+        assert!(recent.repeated("#Rust", "hi", window, now + Duration::from_secs(4)));
+        assert!(!recent.repeated("#go", "hi", window, now));
+        assert!(!recent.repeated("#rust", "hi", window, now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn nicks_fold_by_the_irc_casemapping() {
+        assert_eq!(fold_nick("Alice[m]\\~"), "alice{m}|^");
+        assert!(same_nick("[Bot]", "{bot}"));
+        assert!(!same_nick("alice", "alice_"));
+        assert!(is_admin(&["Op[1]".to_string()], &[], None, "op{1}", None));
+        assert_eq!(history_role("Bot{}: hi", "bot[]"), mini_openai::ROLE_ASSISTANT);
+    }
+
+    #[test]
+    fn filters_run_in_order_until_one_drops_the_message() {
+        let filters: Vec<Box<dyn MessageFilter>> = vec![
+            Box::new(|_: &str, msg: &str| Some(msg.trim().to_string())),
+            Box::new(|sender: &str, msg: &str| (sender != "spammer").then(|| msg.to_uppercase())),
+        ];
+        assert_eq!(filter_message(&filters, "alice", " hi "), Some("HI".to_string()));
+        assert_eq!(filter_message(&filters, "spammer", "buy now"), None);
+        assert_eq!(filter_message(&[], "alice", "hi"), Some("hi".to_string()));
+    }
+
+    #[test]
+    fn folds_nicks_wherever_they_are_compared() {
+        assert!(mentions("hi {bot}", "[bot]", true));
+        assert_eq!(strip_nick_prefix("{Bot}: hi", "[bot]"), Some("hi"));
+
+        let mut addressed = turn(&[("{Alice}", "hello")]);
+        addressed.addressed_by.insert(fold_nick("[alice]"));
+        assert!(addressed.mentions(Some("[ALICE]"), "bot", true));
+        assert!(!addressed.mentions(Some("bob"), "bot", true));
+
+        let route = ReplyRoute::parse("#rust,from=[bob]>#ops").unwrap();
+        assert!(route.matches(&turn(&[("{Bob}", "hi")])));
+
+        let mut pending = PendingMessages::new(None, BufferBy::Sender, Generations::default());
+        pending.push("#rust", "[alice]", "one", true);
+        pending.push("#rust", "{ALICE}", "two", false);
+        let turns = pending.flush_ready(Instant::now() + Duration::from_secs(1));
+        assert_eq!(turns.len(), 1);
+        assert_eq!(turns[0].messages, vec![("[alice]".to_string(), "one\ntwo".to_string())]);
+        assert!(turns[0].mentions(Some("{alice}"), "bot", true));
+    }
+}